@@ -1,71 +1,415 @@
+//! Architecture-dependent operations.
+//!
+//! Locks read the CPU id, control local interrupts and order memory accesses through an [`Arch`]
+//! backend. [`DefaultArch`] is used unless the kernel registers its own backend with
+//! [`set_arch`], e.g. when the register read by [`DefaultArch::cpu_id`] is taken for other use.
+
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{self, AtomicBool, Ordering},
+};
+
+/// CPU id, interrupt and memory barrier operations of an architecture.
+///
+/// All methods are called with arbitrary interrupt state, and must neither take locks of this
+/// crate nor allocate memory.
+pub trait Arch: Sync {
+    /// Gets the id of the current CPU, which must be less than [`NCPU`](crate::NCPU).
+    fn cpu_id(&self) -> usize;
+
+    /// Interrupt on
+    fn intr_on(&self);
+
+    /// Interrupt off
+    fn intr_off(&self);
+
+    /// Gets if interrupt is enabled
+    fn intr_get(&self) -> bool;
+
+    /// Prevents the memory reordering of any read which precedes it in program order
+    /// with any read which follows it in program order, usually used after a read.
+    fn smp_rmb(&self) {
+        atomic::fence(Ordering::Acquire);
+    }
+
+    /// Prevents the memory reordering of any write which precedes it in program order
+    /// with any write which follows it in program order, usually used before a write.
+    fn smp_wmb(&self) {
+        atomic::fence(Ordering::Release);
+    }
+
+    /// Prevents the memory reordering of any read or write which precedes it in program order
+    /// with any read or write which follows it in program order.
+    fn smp_mb(&self) {
+        atomic::fence(Ordering::AcqRel);
+    }
+}
+
+/// The built-in [`Arch`] backend of the target.
+///
+/// - riscv64 and riscv32: CPU id in `tp`, interrupts controlled by `sstatus.SIE`. With the
+///   `machine-mode` feature, CPU id in `mhartid`, interrupts controlled by `mstatus.MIE`.
+/// - aarch64: CPU id in `MPIDR_EL1.Aff0`, IRQs masked by `DAIF.I`.
+/// - x86_64: CPU id at `gs:0`, interrupts controlled by `RFLAGS.IF`.
+/// - loongarch64: CPU id in the `CPUID` CSR, interrupts controlled by `CRMD.IE`.
+/// - hosted targets: CPU id 0, interrupts are never enabled.
+/// - other bare-metal targets: a compile error, unless the `single-core-no-irq` feature declares
+///   that the kernel runs on a single CPU without interrupts, where CPU id is 0 and interrupts are
+///   never enabled as on hosted targets, or the `custom-arch` feature declares that the kernel
+///   registers its own backend with [`set_arch`], where using a lock before panics.
+///
+/// Multiple CPUs on the `single-core-no-irq` fallback cannot be detected by their CPU ids, so they
+/// are only reported by [`init`](crate::init): kernels not calling it are not checked.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultArch;
+
 cfg_if::cfg_if! {
-    if #[cfg(all(target_os = "none", any(target_arch = "riscv64")))] {
-        #[allow(dead_code)]
+    if #[cfg(all(target_os = "none", any(target_arch = "riscv64", target_arch = "riscv32")))] {
+        #[cfg(not(feature = "machine-mode"))]
         use riscv::register::sstatus;
+        #[cfg(feature = "machine-mode")]
+        use riscv::register::{mhartid, mstatus};
 
-        /// Gets CPU id from `tp` register. Remember to avoid using
-        /// `tp` in your kernel.
-        pub(crate) fn cpu_id() -> usize {
-            let mut cpu_id;
-            unsafe {
-                core::arch::asm!("mv {0}, tp", out(reg) cpu_id);
+        impl Arch for DefaultArch {
+            /// Gets CPU id from `tp` register. Remember to avoid using
+            /// `tp` in your kernel, or register another [`Arch`].
+            #[cfg(not(feature = "machine-mode"))]
+            fn cpu_id(&self) -> usize {
+                let mut cpu_id;
+                unsafe {
+                    core::arch::asm!("mv {0}, tp", out(reg) cpu_id);
+                }
+                cpu_id
             }
-            cpu_id
-        }
 
-        /// Interrupt on
-        pub(crate) fn intr_on() {
-            unsafe { sstatus::set_sie() };
-        }
+            /// Gets CPU id from `mhartid` register.
+            #[cfg(feature = "machine-mode")]
+            fn cpu_id(&self) -> usize {
+                mhartid::read()
+            }
 
-        /// Interrupt off
-        pub(crate) fn intr_off() {
-            unsafe { sstatus::clear_sie() };
-        }
+            fn intr_on(&self) {
+                #[cfg(not(feature = "machine-mode"))]
+                unsafe { sstatus::set_sie() };
+                #[cfg(feature = "machine-mode")]
+                unsafe { mstatus::set_mie() };
+            }
 
-        /// Gets if interrupt is enabled
-        pub(crate) fn intr_get() -> bool {
-            sstatus::read().sie()
-        }
+            fn intr_off(&self) {
+                #[cfg(not(feature = "machine-mode"))]
+                unsafe { sstatus::clear_sie() };
+                #[cfg(feature = "machine-mode")]
+                unsafe { mstatus::clear_mie() };
+            }
 
-        /// Prevents the memory reordering of any read which precedes it in program order
-        /// with any read which follows it in program order, usually used after a read.
-        pub(crate) fn smp_rmb() {
-            unsafe { core::arch::asm!("fence r, r"); }
-        }
+            fn intr_get(&self) -> bool {
+                #[cfg(not(feature = "machine-mode"))]
+                return sstatus::read().sie();
+                #[cfg(feature = "machine-mode")]
+                return mstatus::read().mie();
+            }
+
+            fn smp_rmb(&self) {
+                unsafe { core::arch::asm!("fence r, r"); }
+            }
+
+            fn smp_wmb(&self) {
+                unsafe { core::arch::asm!("fence w, w"); }
+            }
 
-        /// Prevents the memory reordering of any read which precedes it in program order
-        /// with any read which follows it in program order, usually used after a read.
-        pub(crate) fn smp_wmb() {
-            unsafe { core::arch::asm!("fence w, w"); }
+            fn smp_mb(&self) {
+                unsafe { core::arch::asm!("fence rw, rw"); }
+            }
         }
+    } else if #[cfg(all(target_os = "none", target_arch = "aarch64"))] {
+        /// IRQ mask bit of `DAIF`.
+        const DAIF_I: usize = 1 << 7;
+
+        impl Arch for DefaultArch {
+            /// Gets CPU id from the affinity level 0 of `MPIDR_EL1`, which is the core number
+            /// within a cluster. Register another [`Arch`] reading e.g. `TPIDR_EL1` on
+            /// multi-cluster boards.
+            fn cpu_id(&self) -> usize {
+                let mut mpidr: usize;
+                unsafe {
+                    core::arch::asm!("mrs {0}, mpidr_el1", out(reg) mpidr);
+                }
+                mpidr & 0xff
+            }
+
+            fn intr_on(&self) {
+                unsafe { core::arch::asm!("msr daifclr, #2"); }
+            }
+
+            fn intr_off(&self) {
+                unsafe { core::arch::asm!("msr daifset, #2"); }
+            }
 
-        /// Prevents the memory reordering of any read which precedes it in program order
-        /// with any read which follows it in program order, usually used after a read.
-        pub(crate) fn smp_mb() {
-            unsafe { core::arch::asm!("fence rw, rw"); }
+            fn intr_get(&self) -> bool {
+                let mut daif: usize;
+                unsafe {
+                    core::arch::asm!("mrs {0}, daif", out(reg) daif);
+                }
+                daif & DAIF_I == 0
+            }
+
+            fn smp_rmb(&self) {
+                unsafe { core::arch::asm!("dmb ishld"); }
+            }
+
+            fn smp_wmb(&self) {
+                unsafe { core::arch::asm!("dmb ishst"); }
+            }
+
+            fn smp_mb(&self) {
+                unsafe { core::arch::asm!("dmb ish"); }
+            }
         }
-    } else {
-        use core::sync::atomic;
+    } else if #[cfg(all(target_os = "none", target_arch = "x86_64"))] {
+        use core::sync::atomic::compiler_fence;
+
+        /// Interrupt enable flag of `RFLAGS`.
+        const RFLAGS_IF: usize = 1 << 9;
+
+        impl Arch for DefaultArch {
+            /// Gets CPU id from the first word of the per-CPU area at the `GS` base. Remember to
+            /// point `IA32_GS_BASE` at an area starting with the CPU id on every CPU at boot, and
+            /// to `swapgs` on kernel entry, or register another [`Arch`] reading e.g. the local
+            /// APIC id.
+            fn cpu_id(&self) -> usize {
+                let mut cpu_id;
+                unsafe {
+                    core::arch::asm!(
+                        "mov {0}, gs:[0]",
+                        out(reg) cpu_id,
+                        options(nostack, readonly, preserves_flags)
+                    );
+                }
+                cpu_id
+            }
+
+            fn intr_on(&self) {
+                unsafe { core::arch::asm!("sti"); }
+            }
+
+            fn intr_off(&self) {
+                unsafe { core::arch::asm!("cli"); }
+            }
+
+            fn intr_get(&self) -> bool {
+                let mut rflags: usize;
+                unsafe {
+                    core::arch::asm!("pushfq; pop {0}", out(reg) rflags);
+                }
+                rflags & RFLAGS_IF != 0
+            }
+
+            /// Loads are not reordered with other loads on x86_64, so only the compiler is fenced.
+            fn smp_rmb(&self) {
+                compiler_fence(Ordering::Acquire);
+            }
+
+            /// Stores are not reordered with other stores on x86_64, so only the compiler is fenced.
+            fn smp_wmb(&self) {
+                compiler_fence(Ordering::Release);
+            }
 
-        pub(crate) fn cpu_id() -> usize {
-            0
+            fn smp_mb(&self) {
+                unsafe { core::arch::asm!("mfence"); }
+            }
         }
-        pub(crate) fn intr_on() {}
+    } else if #[cfg(all(target_os = "none", target_arch = "loongarch64"))] {
+        /// Global interrupt enable bit of `CRMD`.
+        const CRMD_IE: usize = 1 << 2;
+
+        impl Arch for DefaultArch {
+            /// Gets CPU id from the `CPUID` CSR. Register another [`Arch`] reading e.g. `$r21`
+            /// if your kernel keeps a logical CPU id there.
+            fn cpu_id(&self) -> usize {
+                let mut cpuid: usize;
+                unsafe {
+                    core::arch::asm!("csrrd {0}, 0x20", out(reg) cpuid);
+                }
+                cpuid & 0x1ff
+            }
+
+            fn intr_on(&self) {
+                unsafe {
+                    core::arch::asm!("csrxchg {0}, {1}, 0x0", inout(reg) CRMD_IE => _, in(reg) CRMD_IE);
+                }
+            }
+
+            fn intr_off(&self) {
+                unsafe {
+                    core::arch::asm!("csrxchg {0}, {1}, 0x0", inout(reg) 0usize => _, in(reg) CRMD_IE);
+                }
+            }
+
+            fn intr_get(&self) -> bool {
+                let mut crmd: usize;
+                unsafe {
+                    core::arch::asm!("csrrd {0}, 0x0", out(reg) crmd);
+                }
+                crmd & CRMD_IE != 0
+            }
 
-        pub(crate) fn intr_off() {}
+            fn smp_rmb(&self) {
+                unsafe { core::arch::asm!("dbar 0"); }
+            }
 
-        pub(crate) fn intr_get() -> bool {
-            false
+            fn smp_wmb(&self) {
+                unsafe { core::arch::asm!("dbar 0"); }
+            }
+
+            fn smp_mb(&self) {
+                unsafe { core::arch::asm!("dbar 0"); }
+            }
         }
-        pub(crate) fn smp_rmb() {
-            atomic::fence(atomic::Ordering::Acquire);
+    } else if #[cfg(all(
+        target_os = "none",
+        feature = "custom-arch",
+        not(feature = "single-core-no-irq")
+    ))] {
+        /// Panics, since the kernel has not registered its backend yet.
+        #[cold]
+        #[track_caller]
+        fn unregistered() -> ! {
+            panic!("kernel-sync used before `set_arch` registered the `Arch` backend (`custom-arch`)");
         }
-        pub(crate) fn smp_wmb() {
-            atomic::fence(atomic::Ordering::Release);
+
+        impl Arch for DefaultArch {
+            fn cpu_id(&self) -> usize {
+                unregistered()
+            }
+
+            fn intr_on(&self) {
+                unregistered()
+            }
+
+            fn intr_off(&self) {
+                unregistered()
+            }
+
+            fn intr_get(&self) -> bool {
+                unregistered()
+            }
         }
-        pub(crate) fn smp_mb() {
-            atomic::fence(atomic::Ordering::AcqRel);
+    } else {
+        #[cfg(all(target_os = "none", not(feature = "single-core-no-irq")))]
+        compile_error!(
+            "kernel-sync has no `Arch` backend for this architecture: enable the `custom-arch` \
+             feature and register a backend with `set_arch` at boot, or enable the \
+             `single-core-no-irq` feature only if the kernel runs on a single CPU without interrupts"
+        );
+
+        impl Arch for DefaultArch {
+            fn cpu_id(&self) -> usize {
+                0
+            }
+
+            fn intr_on(&self) {}
+
+            fn intr_off(&self) {}
+
+            fn intr_get(&self) -> bool {
+                false
+            }
         }
     }
 }
+
+/// Whether [`DefaultArch`] is the fallback of a bare-metal target without backend, which neither
+/// tells CPUs apart nor disables interrupts.
+const SINGLE_CORE_NO_IRQ: bool = cfg!(all(
+    target_os = "none",
+    not(any(
+        target_arch = "riscv64",
+        target_arch = "riscv32",
+        target_arch = "aarch64",
+        target_arch = "x86_64",
+        target_arch = "loongarch64"
+    ))
+));
+
+/// Whether a backend has been registered by [`set_arch`].
+static ARCH_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// The registered [`Arch`] backend.
+static ARCH: SyncUnsafeCell<&'static dyn Arch> = SyncUnsafeCell::new(&DefaultArch);
+
+/// Registers the [`Arch`] backend used by all locks in place of [`DefaultArch`].
+///
+/// # Safety
+///
+/// Must be called at boot on a single CPU, with interrupts disabled and before any lock or
+/// [`PerCpu`](crate::PerCpu) variable of this crate is used, since interrupt states saved by the
+/// previous backend are restored by the new one.
+pub unsafe fn set_arch(arch: &'static dyn Arch) {
+    *ARCH.get() = arch;
+    ARCH_REGISTERED.store(true, Ordering::Release);
+}
+
+/// Asserts that `ncpu` CPUs can be told apart by the registered backend.
+///
+/// # Panics
+///
+/// Panics if more than one CPU is found while no backend is registered on a target without
+/// built-in one.
+pub(crate) fn check_ncpu(ncpu: usize) {
+    assert!(
+        !SINGLE_CORE_NO_IRQ || ncpu == 1 || ARCH_REGISTERED.load(Ordering::Acquire),
+        "{} CPUs found, but the fallback `Arch` backend of kernel-sync supports a single CPU; \
+         call `set_arch` with an `Arch` backend first",
+        ncpu
+    );
+}
+
+/// Returns `true` if CPU ids tell the threads holding locks apart: always on bare-metal targets, but
+/// only with a registered backend on hosted targets, where [`DefaultArch`] reports CPU 0 for all
+/// threads.
+#[inline(always)]
+pub(crate) fn distinct_cpu_ids() -> bool {
+    cfg!(target_os = "none") || ARCH_REGISTERED.load(Ordering::Relaxed)
+}
+
+/// Returns the registered [`Arch`] backend.
+#[inline(always)]
+fn arch() -> &'static dyn Arch {
+    // Only written by `set_arch` before any concurrent access.
+    unsafe { *ARCH.get() }
+}
+
+#[inline(always)]
+pub(crate) fn cpu_id() -> usize {
+    arch().cpu_id()
+}
+
+#[inline(always)]
+pub(crate) fn intr_on() {
+    arch().intr_on()
+}
+
+#[inline(always)]
+pub(crate) fn intr_off() {
+    arch().intr_off()
+}
+
+#[inline(always)]
+pub(crate) fn intr_get() -> bool {
+    arch().intr_get()
+}
+
+#[inline(always)]
+pub(crate) fn smp_rmb() {
+    arch().smp_rmb()
+}
+
+#[inline(always)]
+pub(crate) fn smp_wmb() {
+    arch().smp_wmb()
+}
+
+#[inline(always)]
+pub(crate) fn smp_mb() {
+    arch().smp_mb()
+}
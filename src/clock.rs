@@ -0,0 +1,81 @@
+//! Monotonic time source.
+//!
+//! Timing-based features, e.g. the hold times measured by the `lock-stat` feature, read time
+//! through a [`Clock`]. [`DefaultClock`] is used unless the kernel registers its own clock with
+//! [`set_clock`], e.g. on targets without a built-in one, or to read a clock of a known frequency.
+
+use core::cell::SyncUnsafeCell;
+
+/// A monotonic tick counter.
+///
+/// Ticks have no fixed unit, e.g. cycles of the platform timer. [`Clock::now`] is called with
+/// arbitrary interrupt state, possibly while holding spin locks, and must neither take locks of
+/// this crate nor allocate memory.
+pub trait Clock: Sync {
+    /// Reads the current tick, never less than the value read before on any CPU.
+    fn now(&self) -> u64;
+}
+
+/// The built-in [`Clock`] of the target.
+///
+/// - riscv64 and riscv32: the `time` CSR, counting at the platform timer frequency, e.g. 10 MHz on
+///   QEMU `virt`. Firmware running in machine mode on cores not implementing the CSR must register
+///   a clock reading `mtime` instead.
+/// - hosted targets with the `std` feature: nanoseconds elapsed since the first read, measured by
+///   `std::time::Instant`.
+/// - other targets: always 0, so that elapsed times stay zero unless a clock is registered.
+///   Kernel targets, e.g. custom target JSONs or `*-uefi`, lack `std` whatever their OS.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultClock;
+
+cfg_if::cfg_if! {
+    if #[cfg(all(target_os = "none", any(target_arch = "riscv64", target_arch = "riscv32")))] {
+        use riscv::register::time;
+
+        impl Clock for DefaultClock {
+            fn now(&self) -> u64 {
+                time::read64()
+            }
+        }
+    } else if #[cfg(all(feature = "std", not(target_os = "none")))] {
+        extern crate std;
+
+        use std::{sync::OnceLock, time::Instant};
+
+        /// Instant of the first read, which ticks are counted from.
+        static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+        impl Clock for DefaultClock {
+            fn now(&self) -> u64 {
+                let elapsed = EPOCH.get_or_init(Instant::now).elapsed();
+                elapsed.as_nanos().try_into().unwrap_or(u64::MAX)
+            }
+        }
+    } else {
+        impl Clock for DefaultClock {
+            fn now(&self) -> u64 {
+                0
+            }
+        }
+    }
+}
+
+/// The registered [`Clock`].
+static CLOCK: SyncUnsafeCell<&'static dyn Clock> = SyncUnsafeCell::new(&DefaultClock);
+
+/// Registers the [`Clock`] used by all timing-based features in place of [`DefaultClock`].
+///
+/// # Safety
+///
+/// Must be called at boot on a single CPU, before any lock of this crate is used, since ticks read
+/// from the previous clock are compared with ticks of the new one.
+pub unsafe fn set_clock(clock: &'static dyn Clock) {
+    *CLOCK.get() = clock;
+}
+
+/// Reads the current tick of the registered [`Clock`].
+#[inline(always)]
+pub(crate) fn now() -> u64 {
+    // Only written by `set_clock` before any concurrent access.
+    unsafe { *CLOCK.get() }.now()
+}
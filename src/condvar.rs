@@ -0,0 +1,146 @@
+//! A naive condition variable.
+//!
+//! A condition variable atomically releases a lock and puts the current thread to sleep until it
+//! is notified, just like `sleep(chan, lk)` in xv6. Both [`SpinLockGuard`]s and [`SleepLockGuard`]s
+//! can be waited on.
+
+use core::{fmt, marker::PhantomData, ops::DerefMut};
+
+use crate::{
+    id::LockId,
+    irq::IrqPolicy,
+    sleeplock::{Sched, SleepLock, SleepLockGuard},
+    spinlock::{SpinLock, SpinLockGuard},
+    waitqueue::{sleep, WaitQueue},
+};
+
+/// Lock guards that can be released and reacquired by [`Condvar::wait`].
+pub trait CondvarGuard<'a, S: Sched>: Sized {
+    /// The lock this guard was created from.
+    type Lock: ?Sized + 'a;
+
+    /// Releases the guard, returning the lock it was created from.
+    fn unlock(guard: Self) -> &'a Self::Lock;
+
+    /// Reacquires the lock on behalf of `thread`.
+    fn relock(lock: &'a Self::Lock, thread: &SpinLock<S>) -> Self;
+}
+
+impl<'a, T: ?Sized + 'a, P: IrqPolicy + 'a, S: Sched> CondvarGuard<'a, S>
+    for SpinLockGuard<'a, T, P>
+{
+    type Lock = SpinLock<T, P>;
+
+    fn unlock(guard: Self) -> &'a Self::Lock {
+        let lock = guard.lock;
+        drop(guard);
+        lock
+    }
+
+    fn relock(lock: &'a Self::Lock, _thread: &SpinLock<S>) -> Self {
+        lock.lock()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, S: Sched> CondvarGuard<'a, S> for SleepLockGuard<'a, T, S> {
+    type Lock = SleepLock<T, S>;
+
+    fn unlock(guard: Self) -> &'a Self::Lock {
+        let lock = guard.lock;
+        drop(guard);
+        lock
+    }
+
+    fn relock(lock: &'a Self::Lock, thread: &SpinLock<S>) -> Self {
+        lock.lock(thread)
+    }
+}
+
+/// A [condition variable](https://en.wikipedia.org/wiki/Monitor_(synchronization)#Condition_variables)
+/// putting threads to sleep until a condition becomes true.
+pub struct Condvar<S: Sched> {
+    phantom: PhantomData<S>,
+
+    /// [`SpinLock`] protecting this [`Condvar`] against lost wakeups.
+    inner: SpinLock<CondvarInner<S>>,
+}
+
+/// Inner info protected by lock.
+struct CondvarInner<S: Sched> {
+    /// A unique identifier of this [`Condvar`].
+    id: LockId,
+
+    /// Threads sleeping on this [`Condvar`].
+    waiters: WaitQueue<S>,
+}
+
+impl<S: Sched> Condvar<S> {
+    /// Creates a new [`Condvar`].
+    #[inline(always)]
+    pub const fn new() -> Self {
+        Condvar {
+            phantom: PhantomData,
+            inner: SpinLock::untracked(CondvarInner {
+                id: LockId::new(),
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Releases the lock held by `guard` and puts `thread` to sleep until this [`Condvar`] is
+    /// notified, then reacquires the lock.
+    ///
+    /// The lock is released only after this [`Condvar`] is locked, and notifiers must lock it
+    /// too, so a notification sent after the caller checked its condition is never lost. Spurious
+    /// wakeups are possible, thus callers should check their condition in a loop, or use
+    /// [`Condvar::wait_while`].
+    pub fn wait<'a, G: CondvarGuard<'a, S>>(&self, guard: G, thread: &SpinLock<S>) -> G {
+        let mut inner = self.inner.lock();
+        let id = inner.id.get();
+        let lock = G::unlock(guard);
+
+        drop(sleep(inner, |inner| &mut inner.waiters, id, thread));
+
+        G::relock(lock, thread)
+    }
+
+    /// Blocks `thread` on this [`Condvar`] as long as `condition` returns `true`, returning the
+    /// reacquired guard once it returns `false`.
+    pub fn wait_while<'a, T, G, F>(&self, mut guard: G, thread: &SpinLock<S>, mut condition: F) -> G
+    where
+        T: ?Sized + 'a,
+        G: CondvarGuard<'a, S> + DerefMut<Target = T>,
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard, thread);
+        }
+        guard
+    }
+
+    /// Wakes up one thread blocked on this [`Condvar`].
+    #[inline(always)]
+    pub fn notify_one(&self) {
+        // Must be called without any thread lock.
+        self.inner.lock().waiters.wake_one();
+    }
+
+    /// Wakes up all threads blocked on this [`Condvar`].
+    #[inline(always)]
+    pub fn notify_all(&self) {
+        // Must be called without any thread lock.
+        self.inner.lock().waiters.wake_all();
+    }
+}
+
+impl<S: Sched> Default for Condvar<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Sched> fmt::Debug for Condvar<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Condvar {{ .. }}")
+    }
+}
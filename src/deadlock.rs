@@ -0,0 +1,223 @@
+//! Spin-timeout deadlock detector, enabled by the `spin-timeout` feature.
+//!
+//! A [`SpinLock`](crate::SpinLock) records the CPU holding it and where it was acquired. A waiter
+//! counts its spin iterations, and once they reach the threshold set by [`set_spin_timeout`], it
+//! reports the owner and both call sites to the handler registered by [`set_spin_timeout_handler`],
+//! or panics if there is none. The waiter keeps spinning if the handler returns, and reports again
+//! after another threshold of iterations.
+//!
+//! The owner CPU is also recorded in debug builds, so that a CPU acquiring a lock it already holds
+//! panics instead of spinning forever.
+//!
+//! Without the feature, locks carry no acquisition site and the hooks are compiled out. Release
+//! builds without the feature carry no owner either.
+
+use core::{
+    fmt,
+    panic::Location,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+/// Spin iterations of a waiter before it is reported, unless changed by [`set_spin_timeout`].
+pub const DEFAULT_SPIN_TIMEOUT: usize = 100_000_000;
+
+/// Threshold set by [`set_spin_timeout`].
+static SPIN_TIMEOUT: AtomicUsize = AtomicUsize::new(DEFAULT_SPIN_TIMEOUT);
+
+/// Handler set by [`set_spin_timeout_handler`], as a `fn(&SpinTimeout)`, or null to panic.
+static HANDLER: AtomicPtr<()> = AtomicPtr::new(null_mut());
+
+/// A waiter which has spun for too long on a lock, see [`set_spin_timeout`].
+#[derive(Debug, Clone, Copy)]
+pub struct SpinTimeout {
+    /// CPU holding the lock, or `None` if it has not recorded itself yet.
+    pub owner: Option<usize>,
+
+    /// Where the lock was acquired by its owner.
+    pub owner_site: Option<&'static Location<'static>>,
+
+    /// CPU waiting for the lock.
+    pub cpu: usize,
+
+    /// Where the waiter is acquiring the lock.
+    pub site: &'static Location<'static>,
+
+    /// Spin iterations of the waiter so far.
+    pub spins: usize,
+}
+
+impl fmt::Display for SpinTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "spin lock timeout: CPU {} acquiring at {} has spun {} times, ",
+            self.cpu, self.site, self.spins
+        )?;
+        match (self.owner, self.owner_site) {
+            (Some(owner), Some(site)) => write!(f, "held by CPU {} acquired at {}", owner, site),
+            _ => write!(f, "owner unknown"),
+        }
+    }
+}
+
+/// Sets the spin iterations of a waiter before it is reported, [`DEFAULT_SPIN_TIMEOUT`] by
+/// default.
+///
+/// # Panics
+///
+/// Panics if `spins` is zero.
+pub fn set_spin_timeout(spins: usize) {
+    assert!(spins > 0, "spin timeout must be positive");
+    SPIN_TIMEOUT.store(spins, Ordering::Relaxed);
+}
+
+/// Registers the handler called by waiters which have spun for too long, in place of panicking.
+///
+/// The handler is called with the interrupt state of the waiter, usually disabled, and must not
+/// take the lock being waited for. The waiter keeps spinning once it returns.
+pub fn set_spin_timeout_handler(handler: fn(&SpinTimeout)) {
+    HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+/// Reports a timeout to the registered handler, or panics.
+#[cold]
+fn report(timeout: &SpinTimeout) {
+    let handler = HANDLER.load(Ordering::Acquire);
+    if handler.is_null() {
+        panic!("{}", timeout);
+    }
+    // Only stored from a `fn(&SpinTimeout)` by `set_spin_timeout_handler`.
+    let handler: fn(&SpinTimeout) = unsafe { core::mem::transmute(handler) };
+    handler(timeout);
+}
+
+/// No CPU holds the lock.
+#[cfg(any(debug_assertions, feature = "spin-timeout"))]
+const NO_OWNER: usize = usize::MAX;
+
+/// The CPU holding a lock, recorded in debug builds or with the `spin-timeout` feature, and where
+/// it was acquired, recorded with the feature.
+#[derive(Debug)]
+pub(crate) struct Owner {
+    #[cfg(any(debug_assertions, feature = "spin-timeout"))]
+    cpu: AtomicUsize,
+
+    #[cfg(feature = "spin-timeout")]
+    site: AtomicPtr<Location<'static>>,
+}
+
+impl Owner {
+    pub(crate) const fn new() -> Self {
+        Owner {
+            #[cfg(any(debug_assertions, feature = "spin-timeout"))]
+            cpu: AtomicUsize::new(NO_OWNER),
+            #[cfg(feature = "spin-timeout")]
+            site: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Records the current CPU as the owner, acquiring at the caller's location. Must be called
+    /// with the lock held.
+    #[inline(always)]
+    #[cfg_attr(feature = "spin-timeout", track_caller)]
+    pub(crate) fn set(&self) {
+        #[cfg(feature = "spin-timeout")]
+        {
+            let site = Location::caller() as *const _ as *mut _;
+            self.site.store(site, Ordering::Relaxed);
+        }
+        #[cfg(any(debug_assertions, feature = "spin-timeout"))]
+        self.cpu.store(crate::cpu_id(), Ordering::Relaxed);
+    }
+
+    /// Forgets the owner, before the lock is released.
+    #[inline(always)]
+    pub(crate) fn clear(&self) {
+        #[cfg(any(debug_assertions, feature = "spin-timeout"))]
+        self.cpu.store(NO_OWNER, Ordering::Relaxed);
+    }
+
+    /// Checks that the current CPU does not hold the lock before waiting for it. Must be called
+    /// with the current CPU pinned, e.g. with interrupts disabled, and only for locks whose
+    /// holders cannot be preempted either.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds or with the `spin-timeout` feature if the current CPU holds the lock,
+    /// which would spin forever. Skipped on hosted targets without [`Arch`](crate::arch::Arch)
+    /// backend, where all threads report the same CPU.
+    #[inline(always)]
+    #[track_caller]
+    pub(crate) fn check(&self) {
+        #[cfg(any(debug_assertions, feature = "spin-timeout"))]
+        if crate::arch::distinct_cpu_ids() {
+            let cpu = crate::cpu_id();
+            if self.cpu.load(Ordering::Relaxed) == cpu {
+                self_deadlock(self, cpu);
+            }
+        }
+    }
+}
+
+/// Panics on a lock acquired by the CPU `cpu` holding it.
+#[cfg(any(debug_assertions, feature = "spin-timeout"))]
+#[cold]
+#[track_caller]
+fn self_deadlock(owner: &Owner, cpu: usize) -> ! {
+    #[cfg(feature = "spin-timeout")]
+    {
+        let site = owner.site.load(Ordering::Relaxed);
+        if !site.is_null() {
+            panic!(
+                "self-deadlock: spin lock already held by CPU {}, acquired at {}",
+                cpu,
+                unsafe { &*(site as *const Location<'static>) }
+            );
+        }
+    }
+    panic!("self-deadlock: spin lock already held by CPU {}", cpu);
+}
+
+/// Spin iterations of a waiter, counted with the `spin-timeout` feature.
+#[derive(Debug)]
+pub(crate) struct Spinner {
+    #[cfg(feature = "spin-timeout")]
+    spins: usize,
+}
+
+impl Spinner {
+    #[inline(always)]
+    pub(crate) const fn new() -> Self {
+        Spinner {
+            #[cfg(feature = "spin-timeout")]
+            spins: 0,
+        }
+    }
+
+    /// Counts a spin iteration waiting for the lock held by `owner`, acquiring at the caller's
+    /// location, and reports a timeout once every threshold of iterations.
+    #[inline(always)]
+    #[cfg_attr(feature = "spin-timeout", track_caller)]
+    pub(crate) fn spin(&mut self, owner: &Owner) {
+        #[cfg(feature = "spin-timeout")]
+        {
+            self.spins += 1;
+            if self
+                .spins
+                .is_multiple_of(SPIN_TIMEOUT.load(Ordering::Relaxed))
+            {
+                let cpu = owner.cpu.load(Ordering::Relaxed);
+                let site = owner.site.load(Ordering::Relaxed);
+                report(&SpinTimeout {
+                    owner: (cpu != NO_OWNER).then_some(cpu),
+                    owner_site: (cpu != NO_OWNER && !site.is_null())
+                        .then(|| unsafe { &*(site as *const Location<'static>) }),
+                    cpu: crate::cpu_id(),
+                    site: Location::caller(),
+                    spins: self.spins,
+                });
+            }
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use alloc::vec::Vec;
 
+use crate::spinlock::SpinLock;
+
 pub struct RecycleAllocator {
     current: usize,
     recycled: Vec<usize>,
 }
 
 impl RecycleAllocator {
-    pub fn new(current: usize) -> Self {
+    pub const fn new(current: usize) -> Self {
         Self {
             current,
             recycled: Vec::new(),
@@ -26,3 +28,36 @@ impl RecycleAllocator {
         self.recycled.push(id);
     }
 }
+
+/// Global allocator of the ids of sleeping primitives.
+static SleepLockIDAllocator: SpinLock<RecycleAllocator> =
+    SpinLock::untracked(RecycleAllocator::new(0));
+
+/// A unique identifier of a sleeping primitive, recycled when the primitive is dropped.
+///
+/// The id is allocated on first use, i.e. when a thread is about to sleep on the primitive,
+/// so that primitives can be created in `const` contexts and uncontended ones never take an id.
+#[derive(Debug)]
+pub(crate) struct LockId(Option<usize>);
+
+impl LockId {
+    /// Creates a new [`LockId`] without allocating it.
+    pub const fn new() -> Self {
+        LockId(None)
+    }
+
+    /// Returns the raw id, allocating it at the first call.
+    pub fn get(&mut self) -> usize {
+        *self
+            .0
+            .get_or_insert_with(|| SleepLockIDAllocator.lock().alloc())
+    }
+}
+
+impl Drop for LockId {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            SleepLockIDAllocator.lock().dealloc(id);
+        }
+    }
+}
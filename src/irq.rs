@@ -0,0 +1,279 @@
+//! Local interrupt disabling.
+//!
+//! [`local_irq_save`] disables interrupts on the current CPU and returns an [`IrqGuard`] restoring
+//! them when dropped, so that nested disabling is always balanced. Spin lock guards hold the
+//! guard of their [`IrqPolicy`] while the lock is held, an [`IrqGuard`] by default.
+//!
+//! The interrupt state saved by the outermost guard belongs to the running thread, not the CPU.
+//! A thread switching context while holding a guard must carry it over with [`IrqState`].
+
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    arch::{distinct_cpu_ids, intr_get},
+    pop_off, push_off, CPUs,
+};
+
+/// A token proving that interrupts are disabled on the current CPU.
+///
+/// Interrupts are restored when the last [`IrqGuard`] of the CPU falls out of scope. It must be
+/// dropped on the CPU it was created on, so it can be neither sent nor shared between threads.
+#[derive(Debug)]
+pub struct IrqGuard {
+    _mark: PhantomData<*const ()>,
+}
+
+/// Disables interrupts on the current CPU until the returned guard is dropped.
+///
+/// The interrupt enabling bit is saved by the outermost guard, and restored when it is dropped.
+#[inline(always)]
+pub fn local_irq_save() -> IrqGuard {
+    push_off();
+    IrqGuard { _mark: PhantomData }
+}
+
+impl Drop for IrqGuard {
+    /// The dropping of the guard will restore the previous interrupt enabling bit.
+    fn drop(&mut self) {
+        pop_off();
+    }
+}
+
+/// Interrupt state of a thread saved across a context switch.
+///
+/// A thread switching out with interrupts disabled leaves its interrupt enabling bit in the CPU,
+/// where the next thread would overwrite it. Save it before switching out, and restore it after
+/// switching back, possibly on another CPU:
+///
+/// ```ignore
+/// let irq = IrqState::save();
+/// __switch(curr_ctx(), idle_ctx());
+/// irq.restore();
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[must_use = "the interrupt state must be restored after switching back"]
+pub struct IrqState {
+    /// Were interrupts enabled before the outermost `push_off()`?
+    intena: bool,
+}
+
+impl IrqState {
+    /// Saves the interrupt state of the current thread before switching to another context.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless interrupts are disabled by exactly one [`IrqGuard`] or spin lock, such as
+    /// the lock of the current thread held by the scheduler.
+    #[inline(always)]
+    pub fn save() -> Self {
+        let cpu = unsafe { CPUs.current_unchecked() };
+        if distinct_cpu_ids() {
+            // Interrupt cannot be nesting or set before scheduler.
+            assert!(cpu.noff.load(Ordering::Relaxed) == 1);
+            assert!(!intr_get());
+        }
+        IrqState {
+            intena: cpu.intena.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Restores the interrupt state of the current thread after switching back.
+    #[inline(always)]
+    pub fn restore(self) {
+        // The thread may be scheduled on another CPU.
+        unsafe { CPUs.current_unchecked() }
+            .intena
+            .store(self.intena, Ordering::Relaxed);
+    }
+}
+
+/// A token proving that bottom halves are disabled on the current CPU.
+///
+/// Bottom halves are enabled again when the last [`BhGuard`] of the CPU falls out of scope.
+#[derive(Debug)]
+pub struct BhGuard {
+    _mark: PhantomData<*const ()>,
+}
+
+/// Disables bottom halves on the current CPU until the returned guard is dropped, leaving
+/// interrupts enabled.
+///
+/// The kernel must check [`bh_disabled`] before running deferred interrupt work or preempting the
+/// current thread, e.g. on timer interrupts, and postpone them until bottom halves are enabled.
+#[inline(always)]
+pub fn local_bh_disable() -> BhGuard {
+    CPUs.with(|cpu| cpu.bh_count.fetch_add(1, Ordering::Relaxed));
+    BhGuard { _mark: PhantomData }
+}
+
+/// Returns `true` if bottom halves are disabled on the current CPU.
+///
+/// Must be called with interrupts disabled, e.g. from an interrupt handler.
+#[inline(always)]
+pub fn bh_disabled() -> bool {
+    unsafe { CPUs.current_unchecked() }
+        .bh_count
+        .load(Ordering::Relaxed)
+        != 0
+}
+
+impl Drop for BhGuard {
+    /// The dropping of the guard will enable bottom halves at the outermost level.
+    fn drop(&mut self) {
+        unsafe { BottomHalf::force_exit() };
+    }
+}
+
+/// Interrupt policy of a spin lock, selecting what is disabled on the local CPU while the lock
+/// is held.
+///
+/// A lock must never be taken from a context its policy does not disable, or the CPU deadlocks
+/// when that context interrupts the lock holder.
+pub trait IrqPolicy {
+    /// A token held by lock guards, restoring the local CPU state when dropped.
+    type Guard;
+
+    /// Whether a lock holder may be preempted by another thread on its CPU. Only locks of
+    /// non-preemptible policies are checked for self-deadlocks in debug builds.
+    const PREEMPTIBLE: bool = true;
+
+    /// Disables what this policy protects against on the current CPU.
+    fn enter() -> Self::Guard;
+
+    /// Restores the local CPU state for a guard which has been forgotten, e.g. by `leak`.
+    ///
+    /// # Safety
+    ///
+    /// Must be paired with a forgotten guard of this policy on the current CPU.
+    unsafe fn force_exit();
+}
+
+/// Disables interrupts while the lock is held, for data shared with interrupt handlers.
+///
+/// This is the default policy of all spin locks.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IrqSave;
+
+/// Leaves interrupts enabled while the lock is held, for data never touched from interrupt
+/// context.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoIrq;
+
+/// Disables bottom halves but leaves interrupts enabled while the lock is held, for data shared
+/// with deferred interrupt work, see [`local_bh_disable`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BottomHalf;
+
+impl IrqPolicy for IrqSave {
+    type Guard = IrqGuard;
+
+    const PREEMPTIBLE: bool = false;
+
+    #[inline(always)]
+    fn enter() -> IrqGuard {
+        local_irq_save()
+    }
+
+    #[inline(always)]
+    unsafe fn force_exit() {
+        pop_off();
+    }
+}
+
+impl IrqPolicy for NoIrq {
+    type Guard = ();
+
+    #[inline(always)]
+    fn enter() {}
+
+    #[inline(always)]
+    unsafe fn force_exit() {}
+}
+
+impl IrqPolicy for BottomHalf {
+    type Guard = BhGuard;
+
+    // Preemption is postponed while bottom halves are disabled.
+    const PREEMPTIBLE: bool = false;
+
+    #[inline(always)]
+    fn enter() -> BhGuard {
+        local_bh_disable()
+    }
+
+    #[inline(always)]
+    unsafe fn force_exit() {
+        let old = CPUs.with(|cpu| cpu.bh_count.fetch_sub(1, Ordering::Relaxed));
+        assert!(old >= 1, "bottom halves enabled more times than disabled");
+    }
+}
+
+/// Contexts a lock has been acquired in, recorded in debug builds to catch a lock shared with
+/// interrupt handlers but held with interrupts enabled elsewhere. Such a holder deadlocks once
+/// the interrupt handler on its CPU tries to take the lock.
+#[derive(Debug, Default)]
+pub(crate) struct IrqUsage {
+    /// Has the lock been acquired in an interrupt handler?
+    #[cfg(debug_assertions)]
+    in_irq: AtomicBool,
+
+    /// Has the lock been held with interrupts enabled?
+    #[cfg(debug_assertions)]
+    irq_on: AtomicBool,
+}
+
+impl IrqUsage {
+    pub(crate) const fn new() -> Self {
+        IrqUsage {
+            #[cfg(debug_assertions)]
+            in_irq: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            irq_on: AtomicBool::new(false),
+        }
+    }
+
+    /// Records an acquisition in an interrupt handler.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled before.
+    #[inline(always)]
+    #[track_caller]
+    pub(crate) fn enter_in_irq(&self) {
+        #[cfg(debug_assertions)]
+        {
+            assert!(
+                !intr_get(),
+                "lock taken for interrupt context with interrupts enabled"
+            );
+            self.in_irq.store(true, Ordering::Relaxed);
+            assert!(
+                !self.irq_on.load(Ordering::Relaxed),
+                "lock acquired in interrupt context, but also held with interrupts enabled"
+            );
+        }
+    }
+
+    /// Records an acquisition outside interrupt handlers, after the lock policy is entered.
+    ///
+    /// # Panics
+    ///
+    /// Panics in debug builds if interrupts are enabled and the lock has been acquired in an
+    /// interrupt handler before.
+    #[inline(always)]
+    #[track_caller]
+    pub(crate) fn enter(&self) {
+        #[cfg(debug_assertions)]
+        if intr_get() {
+            self.irq_on.store(true, Ordering::Relaxed);
+            assert!(
+                !self.in_irq.load(Ordering::Relaxed),
+                "lock held with interrupts enabled, but also acquired in interrupt context"
+            );
+        }
+    }
+}
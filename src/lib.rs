@@ -7,50 +7,189 @@
 extern crate alloc;
 
 pub mod arch;
+pub mod clock;
+mod condvar;
+mod deadlock;
 mod id;
-// mod rcu;
+mod irq;
+mod lockdep;
+mod lockstat;
+mod mcslock;
+mod percpu;
+mod rcu;
+mod reentrantlock;
 mod rwlock;
+mod rwsleeplock;
+mod semaphore;
 mod seqlock;
 mod sleeplock;
 mod spinlock;
+mod ticketlock;
+mod waitqueue;
 
-// pub use rcu::{reclamation, wait, RcuCell, RcuDrop, RcuDropFn, RcuReadGuard, RcuType};
+pub use condvar::{Condvar, CondvarGuard};
+#[cfg(feature = "spin-timeout")]
+pub use deadlock::{set_spin_timeout, set_spin_timeout_handler, SpinTimeout, DEFAULT_SPIN_TIMEOUT};
+pub use irq::{
+    bh_disabled, local_bh_disable, local_irq_save, BhGuard, BottomHalf, IrqGuard, IrqPolicy,
+    IrqSave, IrqState, NoIrq,
+};
+#[cfg(feature = "lock-stat")]
+pub use lockstat::{
+    for_each_lock_stat, reset_lock_stats, LockStat, LockStatReport, MAX_LOCK_CLASSES,
+};
+pub use mcslock::{McsLock, McsLockGuard};
+pub use percpu::{PerCpu, PerCpuGuard};
+pub use rcu::{
+    call_rcu, rcu_read_lock, rcu_read_unlock, reclamation, synchronize_rcu, RcuCell, RcuDrop,
+    RcuReadGuard, RcuType,
+};
+pub use reentrantlock::{ReentrantSpinLock, ReentrantSpinLockGuard};
+pub use rwlock::{
+    RwSpinLock, RwSpinLockReadGuard, RwSpinLockUpgradableGuard, RwSpinLockWriteGuard,
+};
+pub use rwsleeplock::{RwSleepLock, RwSleepLockReadGuard, RwSleepLockWriteGuard};
+pub use semaphore::Semaphore;
 pub use seqlock::SeqLock;
 pub use sleeplock::{Sched as SleepLockSched, SleepLock, SleepLockGuard};
 pub use spinlock::{SpinLock, SpinLockGuard};
+pub use ticketlock::{TicketLock, TicketLockGuard};
+pub use waitqueue::WaitQueue;
 
-use arch::*;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-const NCPU: usize = 16;
+use arch::{distinct_cpu_ids, intr_get, intr_off, intr_on};
+
+/// Maximum number of CPUs, configured at build time through the `KERNEL_SYNC_NCPU` environment
+/// variable (16 by default). Per-CPU tables are statically allocated with this size.
+pub const NCPU: usize = match option_env!("KERNEL_SYNC_NCPU") {
+    Some(ncpu) => parse_ncpu(ncpu),
+    None => 16,
+};
+
+/// Parses the decimal value of `KERNEL_SYNC_NCPU` at compile time.
+const fn parse_ncpu(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    assert!(!bytes.is_empty(), "KERNEL_SYNC_NCPU must not be empty");
+    let mut ncpu = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "KERNEL_SYNC_NCPU must be a decimal number"
+        );
+        ncpu = ncpu * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    assert!(ncpu > 0, "KERNEL_SYNC_NCPU must be positive");
+    ncpu
+}
+
+/// Number of CPUs registered at boot, [`NCPU`] until [`init`] is called.
+static NCPU_ONLINE: AtomicUsize = AtomicUsize::new(NCPU);
+
+/// Registers the number of CPUs found at boot, e.g. the hart count in the device tree.
+///
+/// Locks can be used before this function is called, as long as CPU ids are less than [`NCPU`].
+///
+/// # Panics
+///
+/// Panics if `ncpu` is zero or exceeds [`NCPU`], or if multiple CPUs are found on a target without
+/// [`Arch`](arch::Arch) backend, see [`DefaultArch`](arch::DefaultArch). This is the only check of
+/// the `single-core-no-irq` fallback, which cannot tell CPUs apart when locking.
+pub fn init(ncpu: usize) {
+    assert!(
+        ncpu > 0 && ncpu <= NCPU,
+        "{} CPUs found, but kernel-sync supports 1 to {} CPUs; rebuild with KERNEL_SYNC_NCPU={}",
+        ncpu,
+        NCPU,
+        ncpu
+    );
+    arch::check_ncpu(ncpu);
+    NCPU_ONLINE.store(ncpu, Ordering::Release);
+}
+
+/// Returns the number of CPUs registered by [`init`].
+#[inline(always)]
+pub fn ncpu() -> usize {
+    NCPU_ONLINE.load(Ordering::Acquire)
+}
+
+/// Returns the id of the current CPU.
+///
+/// # Panics
+///
+/// Panics if the id is not less than the number of CPUs registered by [`init`].
+#[inline(always)]
+pub(crate) fn cpu_id() -> usize {
+    let id = arch::cpu_id();
+    assert!(
+        id < ncpu(),
+        "CPU id {} out of range: {} CPUs registered, at most {} supported (KERNEL_SYNC_NCPU)",
+        id,
+        ncpu(),
+        NCPU
+    );
+    id
+}
 
 /// Per-CPU state
-#[derive(Debug, Default, Clone, Copy)]
+///
+/// Fields are atomics so that other CPUs can read them through [`PerCpu::for_each_cpu`], but
+/// `noff` and `intena` are only accessed by the owning CPU with interrupts disabled. Use
+/// [`IrqState`] to carry `intena` over a context switch.
+#[derive(Debug, Default)]
 pub struct CPU {
     /// Depth of push_off() nesting.
-    pub noff: usize,
+    pub(crate) noff: AtomicUsize,
 
     /// Were interrupts enabled before push_off()?
-    pub intena: bool,
+    pub(crate) intena: AtomicBool,
+
+    /// Depth of local_bh_disable() nesting.
+    pub(crate) bh_count: AtomicUsize,
+
+    /// Depth of rcu_read_lock() nesting, read by other CPUs waiting for a grace period.
+    pub(crate) rcu_nesting: AtomicUsize,
+
+    /// Latest grace period started before this CPU left its outermost RCU read-side critical section.
+    pub(crate) rcu_qs: AtomicUsize,
+}
+
+impl CPU {
+    /// Creates a new [`CPU`] with interrupts disabled and no RCU reader.
+    pub const fn new() -> Self {
+        CPU {
+            noff: AtomicUsize::new(0),
+            intena: AtomicBool::new(false),
+            bh_count: AtomicUsize::new(0),
+            rcu_nesting: AtomicUsize::new(0),
+            rcu_qs: AtomicUsize::new(0),
+        }
+    }
 }
 
-pub static mut CPUs: [CPU; NCPU] = [CPU {
-    noff: 0,
-    intena: false,
-}; NCPU];
+pub static CPUs: PerCpu<CPU> = PerCpu::from_array([const { CPU::new() }; NCPU]);
 
 /// Save old interrupt enabling bit in CPU local variables and disable interrupt at first
 /// `push_off()`. The depth of nesting is increased by 1.
+///
+/// Prefer [`local_irq_save`], whose guard cannot be left unbalanced.
+///
+/// On hosted targets, interrupts are only controlled through a registered
+/// [`Arch`](arch::Arch) backend telling threads apart, since all threads share the state of CPU 0
+/// otherwise.
 #[inline(always)]
 pub fn push_off() {
-    #[cfg(target_os = "none")]
-    {
+    if distinct_cpu_ids() {
         let old = intr_get();
         intr_off();
-        let cpu = unsafe { &mut CPUs[cpu_id()] };
-        if cpu.noff == 0 {
-            cpu.intena = old;
+        let cpu = unsafe { CPUs.current_unchecked() };
+        let noff = cpu.noff.load(Ordering::Relaxed);
+        if noff == 0 {
+            cpu.intena.store(old, Ordering::Relaxed);
         }
-        cpu.noff += 1;
+        cpu.noff.store(noff + 1, Ordering::Relaxed);
     }
 }
 
@@ -58,14 +197,14 @@ pub fn push_off() {
 /// `pop_off()`. The depth of nesting is decreased by 1.
 #[inline(always)]
 pub fn pop_off() {
-    #[cfg(target_os = "none")]
-    {
-        let cpu = unsafe { &mut CPUs[cpu_id()] };
+    if distinct_cpu_ids() {
+        let cpu = unsafe { CPUs.current_unchecked() };
+        let noff = cpu.noff.load(Ordering::Relaxed);
 
-        assert!(!intr_get() && cpu.noff >= 1);
+        assert!(!intr_get() && noff >= 1);
 
-        cpu.noff -= 1;
-        if cpu.noff == 0 && cpu.intena {
+        cpu.noff.store(noff - 1, Ordering::Relaxed);
+        if noff == 1 && cpu.intena.load(Ordering::Relaxed) {
             intr_on();
         }
     }
@@ -0,0 +1,499 @@
+//! Lock dependency validator, enabled by the `lockdep` feature.
+//!
+//! Every lock belongs to a class, identified by the place the lock is created at, so that all
+//! locks created by the same line of code share a class. Each CPU records the spin locks it holds,
+//! and each thread the sleep locks it holds. Acquiring a lock while holding others adds edges from
+//! the held classes to the acquired one in a global acquisition-order graph. The first edge closing
+//! a cycle is an inversion, e.g. ABBA, which can deadlock, and panics with the acquisition sites
+//! of both orders before the lock is actually taken.
+//!
+//! Without the feature, all hooks are empty, and locks carry no class unless the `lock-stat`
+//! feature is enabled.
+
+use core::panic::Location;
+
+/// Class of a lock, identified by the place the lock is created at, also used by the `lock-stat`
+/// feature.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LockClass {
+    /// Creation site, or `None` for internal locks which are neither validated nor measured.
+    #[cfg(any(feature = "lockdep", feature = "lock-stat"))]
+    key: Option<&'static Location<'static>>,
+}
+
+impl LockClass {
+    /// Creates the class of a lock created at the caller's location.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub(crate) const fn new() -> Self {
+        LockClass {
+            #[cfg(any(feature = "lockdep", feature = "lock-stat"))]
+            key: Some(Location::caller()),
+        }
+    }
+
+    /// Creates the class of an internal lock of this crate, e.g. the inner [`SpinLock`] of a
+    /// sleeping primitive, which is neither validated nor measured.
+    ///
+    /// [`SpinLock`]: crate::SpinLock
+    #[inline(always)]
+    pub(crate) const fn untracked() -> Self {
+        LockClass {
+            #[cfg(any(feature = "lockdep", feature = "lock-stat"))]
+            key: None,
+        }
+    }
+
+    /// Returns the creation site of this class, or `None` for internal locks.
+    #[cfg(any(feature = "lockdep", feature = "lock-stat"))]
+    #[inline(always)]
+    pub(crate) fn key(&self) -> Option<&'static Location<'static>> {
+        self.key
+    }
+}
+
+/// A lock recorded as held, removed from the held locks when dropped.
+#[derive(Debug)]
+pub(crate) struct Held {
+    /// Address of the lock and the thread holding it, for sleep locks.
+    #[cfg(feature = "lockdep")]
+    lock: Option<(usize, Option<usize>)>,
+}
+
+impl Held {
+    /// A lock which is not recorded.
+    #[inline(always)]
+    pub(crate) const fn none() -> Self {
+        Held {
+            #[cfg(feature = "lockdep")]
+            lock: None,
+        }
+    }
+}
+
+#[cfg(feature = "lockdep")]
+impl Drop for Held {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if let Some((lock, thread)) = self.lock.take() {
+            validator::release(lock, thread);
+        }
+    }
+}
+
+/// Validates the acquisition of spin `lock` at the caller's location, before it is taken, and
+/// records it as held by the current CPU.
+///
+/// # Panics
+///
+/// Panics if acquiring `lock` while holding the locks of this CPU inverts an order observed before.
+#[inline(always)]
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub(crate) fn acquire<L: ?Sized>(class: &LockClass, lock: &L) -> Held {
+    #[cfg(feature = "lockdep")]
+    if let Some(key) = class.key {
+        return validator::acquire(key, addr(lock), None, Location::caller(), true);
+    }
+    Held::none()
+}
+
+/// Records spin `lock` acquired without waiting at the caller's location, e.g. by `try_lock`,
+/// which cannot deadlock and is therefore not validated.
+#[inline(always)]
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub(crate) fn acquired<L: ?Sized>(class: &LockClass, lock: &L) -> Held {
+    #[cfg(feature = "lockdep")]
+    if let Some(key) = class.key {
+        return validator::acquire(key, addr(lock), None, Location::caller(), false);
+    }
+    Held::none()
+}
+
+/// Validates the acquisition of sleep `lock` by `thread` at the caller's location, before it is
+/// taken, and records it as held by `thread`.
+///
+/// # Panics
+///
+/// Panics if acquiring `lock` while holding the sleep locks of `thread` inverts an order observed
+/// before.
+#[inline(always)]
+#[cfg_attr(feature = "lockdep", track_caller)]
+pub(crate) fn acquire_sleep<L: ?Sized, S>(class: &LockClass, lock: &L, thread: &S) -> Held {
+    #[cfg(feature = "lockdep")]
+    if let Some(key) = class.key {
+        return validator::acquire(
+            key,
+            addr(lock),
+            Some(addr(thread)),
+            Location::caller(),
+            true,
+        );
+    }
+    Held::none()
+}
+
+/// Removes spin `lock` from the locks held by the current CPU, for locks released without their
+/// guard, e.g. by `force_unlock`.
+#[inline(always)]
+pub(crate) fn release<L: ?Sized>(lock: &L) {
+    #[cfg(feature = "lockdep")]
+    validator::release(addr(lock), None);
+}
+
+/// Removes sleep `lock` from the locks held by any thread, for locks released without their
+/// guard, e.g. by `force_unlock`.
+#[inline(always)]
+pub(crate) fn release_sleep<L: ?Sized>(lock: &L) {
+    #[cfg(feature = "lockdep")]
+    validator::release_sleep(addr(lock));
+}
+
+/// Returns the address identifying a lock or a thread.
+#[cfg(feature = "lockdep")]
+#[inline(always)]
+fn addr<L: ?Sized>(lock: &L) -> usize {
+    lock as *const L as *const () as usize
+}
+
+#[cfg(feature = "lockdep")]
+mod validator {
+    use alloc::{
+        collections::{BTreeMap, VecDeque},
+        format,
+        string::String,
+        vec::Vec,
+    };
+    use core::{
+        cell::UnsafeCell,
+        fmt::Write,
+        panic::Location,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use crate::{irq::local_irq_save, PerCpu, NCPU};
+
+    /// Maximum number of spin locks a CPU can hold at the same time.
+    const MAX_HELD: usize = 32;
+
+    type Key = &'static Location<'static>;
+
+    /// A lock held by a CPU or a thread.
+    #[derive(Debug, Clone, Copy)]
+    struct Entry {
+        /// Address of the lock.
+        lock: usize,
+
+        /// Class of the lock.
+        class: Key,
+
+        /// Where the lock was acquired.
+        site: Key,
+    }
+
+    /// An edge of the acquisition-order graph, recording where it was first observed.
+    #[derive(Debug, Clone, Copy)]
+    struct Edge {
+        /// Where the lock of the source class was acquired.
+        held_site: Key,
+
+        /// Where the lock of the target class was acquired while holding the former.
+        site: Key,
+    }
+
+    /// Spin locks held by a CPU.
+    struct HeldLocks {
+        /// Whether the validator is accessing these locks, also used to skip the locks taken by
+        /// the validator itself on this CPU, e.g. in the allocator.
+        busy: AtomicBool,
+        len: UnsafeCell<usize>,
+        entries: UnsafeCell<[Option<Entry>; MAX_HELD]>,
+    }
+
+    // Only accessed while `busy` is set.
+    unsafe impl Send for HeldLocks {}
+    unsafe impl Sync for HeldLocks {}
+
+    impl HeldLocks {
+        const fn new() -> Self {
+            HeldLocks {
+                busy: AtomicBool::new(false),
+                len: UnsafeCell::new(0),
+                entries: UnsafeCell::new([None; MAX_HELD]),
+            }
+        }
+
+        /// Starts accessing these locks, returning `false` if they are already being accessed.
+        fn enter(&self) -> bool {
+            !self.busy.swap(true, Ordering::Acquire)
+        }
+
+        /// Stops accessing these locks.
+        fn exit(&self) {
+            self.busy.store(false, Ordering::Release);
+        }
+
+        /// Returns the held locks, from the earliest acquired.
+        ///
+        /// # Safety
+        ///
+        /// Must be called between [`HeldLocks::enter`] and [`HeldLocks::exit`].
+        unsafe fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+            let entries = &*self.entries.get();
+            entries[..*self.len.get()].iter().flatten().copied()
+        }
+
+        /// Appends a held lock, returning `false` if [`MAX_HELD`] locks are already held.
+        ///
+        /// # Safety
+        ///
+        /// Must be called between [`HeldLocks::enter`] and [`HeldLocks::exit`].
+        unsafe fn push(&self, entry: Entry) -> bool {
+            let len = &mut *self.len.get();
+            if *len == MAX_HELD {
+                return false;
+            }
+            let entries = &mut *self.entries.get();
+            entries[*len] = Some(entry);
+            *len += 1;
+            true
+        }
+
+        /// Removes a held lock, returning `false` if it is not held.
+        ///
+        /// # Safety
+        ///
+        /// Must be called between [`HeldLocks::enter`] and [`HeldLocks::exit`].
+        unsafe fn remove(&self, lock: usize) -> bool {
+            let len = &mut *self.len.get();
+            let entries = &mut *self.entries.get();
+            // Locks are usually released in reverse order, but not necessarily.
+            match entries[..*len]
+                .iter()
+                .rposition(|entry| entry.is_some_and(|entry| entry.lock == lock))
+            {
+                Some(i) => {
+                    entries.copy_within(i + 1..*len, i);
+                    *len -= 1;
+                    entries[*len] = None;
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    static HELD: PerCpu<HeldLocks> = PerCpu::from_array([const { HeldLocks::new() }; NCPU]);
+
+    /// Global state of the validator.
+    struct Graph {
+        /// Acquisition-order edges between classes.
+        edges: BTreeMap<Key, BTreeMap<Key, Edge>>,
+
+        /// Sleep locks held by each thread.
+        threads: BTreeMap<usize, Vec<Entry>>,
+    }
+
+    /// The [`Graph`] protected by a lock which is not validated itself.
+    struct GraphLock {
+        locked: AtomicBool,
+        graph: UnsafeCell<Graph>,
+    }
+
+    unsafe impl Sync for GraphLock {}
+
+    static GRAPH: GraphLock = GraphLock {
+        locked: AtomicBool::new(false),
+        graph: UnsafeCell::new(Graph {
+            edges: BTreeMap::new(),
+            threads: BTreeMap::new(),
+        }),
+    };
+
+    impl GraphLock {
+        /// Runs `f` with the graph locked.
+        fn with<R>(&self, f: impl FnOnce(&mut Graph) -> R) -> R {
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                core::hint::spin_loop();
+            }
+            let ret = f(unsafe { &mut *self.graph.get() });
+            self.locked.store(false, Ordering::Release);
+            ret
+        }
+    }
+
+    /// Records the acquisition of `lock` by the current CPU, or by `thread` for sleep locks,
+    /// validating it against the held locks if `validate`.
+    pub(super) fn acquire(
+        class: Key,
+        lock: usize,
+        thread: Option<usize>,
+        site: Key,
+        validate: bool,
+    ) -> super::Held {
+        let _irq = local_irq_save();
+        let cpu = unsafe { HELD.current_unchecked() };
+        if !cpu.enter() {
+            return super::Held::none();
+        }
+        let entry = Entry { lock, class, site };
+        let report = GRAPH.with(|graph| {
+            let held: Vec<Entry> = match thread {
+                Some(thread) => graph.threads.get(&thread).cloned().unwrap_or_default(),
+                None => unsafe { cpu.entries() }.collect(),
+            };
+            let report = if validate {
+                held.iter().find_map(|prev| graph.add_edge(prev, &entry))
+            } else {
+                None
+            };
+            // A lock reported is not acquired, as the caller panics.
+            if let (None, Some(thread)) = (&report, thread) {
+                graph.threads.entry(thread).or_default().push(entry);
+            }
+            report
+        });
+        let full = report.is_none() && thread.is_none() && !unsafe { cpu.push(entry) };
+        cpu.exit();
+
+        // Panic only after releasing the graph and the held locks, which stay usable.
+        if let Some(report) = report {
+            panic!("{}", report);
+        }
+        assert!(!full, "lockdep: too many spin locks held by a CPU");
+        super::Held {
+            lock: Some((lock, thread)),
+        }
+    }
+
+    /// Removes `lock` from the locks held by the current CPU, or by `thread` for sleep locks.
+    pub(super) fn release(lock: usize, thread: Option<usize>) {
+        let _irq = local_irq_save();
+        let cpu = unsafe { HELD.current_unchecked() };
+        if !cpu.enter() {
+            return;
+        }
+        let found = match thread {
+            Some(thread) => {
+                GRAPH.with(|graph| {
+                    if let Some(held) = graph.threads.get_mut(&thread) {
+                        if let Some(i) = held.iter().rposition(|entry| entry.lock == lock) {
+                            held.remove(i);
+                        }
+                        if held.is_empty() {
+                            graph.threads.remove(&thread);
+                        }
+                    }
+                });
+                true
+            }
+            None => unsafe { cpu.remove(lock) },
+        };
+        cpu.exit();
+
+        // A spin lock may be released on another CPU, e.g. the lock of a thread released by the
+        // scheduler after a context switch. Only one CPU is accessed at a time to avoid deadlocks.
+        if !found {
+            let id = crate::cpu_id();
+            for other in (0..crate::ncpu())
+                .filter(|&other| other != id)
+                .map(|other| HELD.remote(other))
+            {
+                while !other.enter() {
+                    core::hint::spin_loop();
+                }
+                let found = unsafe { other.remove(lock) };
+                other.exit();
+                if found {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Removes `lock` from the sleep locks held by any thread.
+    pub(super) fn release_sleep(lock: usize) {
+        let _irq = local_irq_save();
+        let cpu = unsafe { HELD.current_unchecked() };
+        if !cpu.enter() {
+            return;
+        }
+        GRAPH.with(|graph| {
+            graph.threads.retain(|_, held| {
+                held.retain(|entry| entry.lock != lock);
+                !held.is_empty()
+            })
+        });
+        cpu.exit();
+    }
+
+    impl Graph {
+        /// Adds the edge from `prev` to `next`, returning a report if it closes a cycle.
+        ///
+        /// The edge is added in either case, so each inversion is reported only once.
+        fn add_edge(&mut self, prev: &Entry, next: &Entry) -> Option<String> {
+            // Locks of the same class, e.g. in an array, are not ordered.
+            if prev.class == next.class
+                || self
+                    .edges
+                    .get(prev.class)
+                    .is_some_and(|edges| edges.contains_key(next.class))
+            {
+                return None;
+            }
+            let cycle = self.path(next.class, prev.class);
+            self.edges.entry(prev.class).or_default().insert(
+                next.class,
+                Edge {
+                    held_site: prev.site,
+                    site: next.site,
+                },
+            );
+            let cycle = cycle?;
+
+            let mut report = format!(
+                "lockdep: possible deadlock, acquiring lock {} at {} while holding lock {} acquired at {}, \
+                 but the reverse order was observed before:",
+                next.class, next.site, prev.class, prev.site
+            );
+            for (from, to, edge) in cycle {
+                let _ = write!(
+                    report,
+                    "\n  lock {} acquired at {} while holding lock {} acquired at {}",
+                    to, edge.site, from, edge.held_site
+                );
+            }
+            Some(report)
+        }
+
+        /// Finds the shortest path of edges from class `from` to class `to`.
+        fn path(&self, from: Key, to: Key) -> Option<Vec<(Key, Key, Edge)>> {
+            // Edge leading to each visited class, found by breadth-first search.
+            let mut parents: BTreeMap<Key, (Key, Edge)> = BTreeMap::new();
+            let mut queue = VecDeque::from([from]);
+            while let Some(class) = queue.pop_front() {
+                if class == to {
+                    let mut path = Vec::new();
+                    let mut class = to;
+                    while class != from {
+                        let (parent, edge) = parents[&class];
+                        path.push((parent, class, edge));
+                        class = parent;
+                    }
+                    path.reverse();
+                    return Some(path);
+                }
+                for (&next, &edge) in self.edges.get(class).into_iter().flatten() {
+                    if next != from && !parents.contains_key(next) {
+                        parents.insert(next, (class, edge));
+                        queue.push_back(next);
+                    }
+                }
+            }
+            None
+        }
+    }
+}
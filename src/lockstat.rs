@@ -0,0 +1,316 @@
+//! Lock contention statistics, enabled by the `lock-stat` feature.
+//!
+//! Statistics are kept per lock class, the place locks are created at as in the lockdep validator,
+//! in a static table of [`MAX_LOCK_CLASSES`] entries filled on first acquisition. Each acquisition
+//! counts spin iterations while the lock is contended, and measures the waiting and holding times
+//! with the registered [`Clock`](crate::clock::Clock).
+//!
+//! Without the feature, all hooks are empty and guards carry no statistics.
+
+use alloc::vec::Vec;
+use core::{
+    cmp::Reverse,
+    fmt,
+    panic::Location,
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{clock::now, lockdep::LockClass};
+
+/// Maximum number of lock classes with statistics. Acquisitions of further classes are not
+/// recorded.
+pub const MAX_LOCK_CLASSES: usize = 512;
+
+/// Statistics of a lock class, see [`for_each_lock_stat`].
+///
+/// Times are measured in ticks of the registered [`Clock`](crate::clock::Clock).
+#[derive(Debug, Clone, Copy)]
+pub struct LockStat {
+    /// Where the locks of this class are created.
+    pub class: &'static Location<'static>,
+
+    /// Number of acquisitions.
+    pub acquisitions: usize,
+
+    /// Number of acquisitions which had to wait for the lock.
+    pub contended: usize,
+
+    /// Total spin iterations of contended acquisitions of spin locks.
+    pub spins_total: usize,
+
+    /// Maximum spin iterations of an acquisition.
+    pub spins_max: usize,
+
+    /// Total time spent waiting for the lock, spinning or sleeping.
+    pub wait_total: usize,
+
+    /// Maximum time spent waiting by an acquisition.
+    pub wait_max: usize,
+
+    /// Total time the lock has been held.
+    pub hold_total: usize,
+
+    /// Maximum time the lock has been held at once.
+    pub hold_max: usize,
+}
+
+/// Statistics of a lock class, updated concurrently by all CPUs.
+#[derive(Debug)]
+struct ClassStat {
+    /// Creation site of the class, or null if this entry is free.
+    key: AtomicPtr<Location<'static>>,
+    acquisitions: AtomicUsize,
+    contended: AtomicUsize,
+    spins_total: AtomicUsize,
+    spins_max: AtomicUsize,
+    wait_total: AtomicUsize,
+    wait_max: AtomicUsize,
+    hold_total: AtomicUsize,
+    hold_max: AtomicUsize,
+}
+
+impl ClassStat {
+    const fn new() -> Self {
+        ClassStat {
+            key: AtomicPtr::new(null_mut()),
+            acquisitions: AtomicUsize::new(0),
+            contended: AtomicUsize::new(0),
+            spins_total: AtomicUsize::new(0),
+            spins_max: AtomicUsize::new(0),
+            wait_total: AtomicUsize::new(0),
+            wait_max: AtomicUsize::new(0),
+            hold_total: AtomicUsize::new(0),
+            hold_max: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the entry of class `key`, claiming a free one on first use, or `None` if the table
+    /// is full.
+    fn get(key: &'static Location<'static>) -> Option<&'static ClassStat> {
+        // Locations of the same call site may be duplicated across codegen units, so they are
+        // compared by value.
+        let hash = key.line() as usize * 31 + key.column() as usize;
+        (0..MAX_LOCK_CLASSES)
+            .map(|i| &CLASSES[(hash + i) % MAX_LOCK_CLASSES])
+            .find(|stat| {
+                let ptr = key as *const _ as *mut _;
+                let current = match stat.key.compare_exchange(
+                    null_mut(),
+                    ptr,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return true,
+                    Err(current) => current,
+                };
+                *unsafe { &*current } == *key
+            })
+    }
+
+    /// Takes a snapshot of this entry, or `None` if it is free.
+    fn snapshot(&self) -> Option<LockStat> {
+        let key = self.key.load(Ordering::Acquire);
+        (!key.is_null()).then(|| LockStat {
+            class: unsafe { &*key },
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            spins_total: self.spins_total.load(Ordering::Relaxed),
+            spins_max: self.spins_max.load(Ordering::Relaxed),
+            wait_total: self.wait_total.load(Ordering::Relaxed),
+            wait_max: self.wait_max.load(Ordering::Relaxed),
+            hold_total: self.hold_total.load(Ordering::Relaxed),
+            hold_max: self.hold_max.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Clears the counters, keeping the class.
+    fn reset(&self) {
+        for counter in [
+            &self.acquisitions,
+            &self.contended,
+            &self.spins_total,
+            &self.spins_max,
+            &self.wait_total,
+            &self.wait_max,
+            &self.hold_total,
+            &self.hold_max,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+static CLASSES: [ClassStat; MAX_LOCK_CLASSES] = [const { ClassStat::new() }; MAX_LOCK_CLASSES];
+
+/// Returns the ticks elapsed since `start`.
+#[inline(always)]
+fn since(start: u64) -> usize {
+    now().saturating_sub(start).try_into().unwrap_or(usize::MAX)
+}
+
+/// Calls `f` with the statistics of every lock class acquired so far.
+pub fn for_each_lock_stat<F>(mut f: F)
+where
+    F: FnMut(&LockStat),
+{
+    for stat in CLASSES.iter().filter_map(ClassStat::snapshot) {
+        f(&stat);
+    }
+}
+
+/// Clears the statistics of all lock classes.
+pub fn reset_lock_stats() {
+    for stat in CLASSES.iter() {
+        stat.reset();
+    }
+}
+
+/// A `/proc/lock_stat`-style report of all lock classes, most contended first, printed with
+/// [`Display`](fmt::Display):
+///
+/// ```ignore
+/// println!("{}", LockStatReport);
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LockStatReport;
+
+impl fmt::Display for LockStatReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut stats = Vec::new();
+        for_each_lock_stat(|stat| stats.push(*stat));
+        stats.sort_by_key(|stat| Reverse((stat.contended, stat.acquisitions)));
+
+        let rule = "-".repeat(165);
+        writeln!(f, "lock_stat version 0.1")?;
+        writeln!(f, "{}", rule)?;
+        writeln!(
+            f,
+            "{:>40} {:>12} {:>12} {:>10} {:>12} {:>14} {:>14} {:>14} {:>14} {:>14}",
+            "class name",
+            "contentions",
+            "acquisitions",
+            "spins-max",
+            "spins-total",
+            "waittime-max",
+            "waittime-total",
+            "holdtime-max",
+            "holdtime-total",
+            "holdtime-avg"
+        )?;
+        writeln!(f, "{}", rule)?;
+        for stat in stats {
+            writeln!(
+                f,
+                "{:>40} {:>12} {:>12} {:>10} {:>12} {:>14} {:>14} {:>14} {:>14} {:>14}",
+                alloc::format!("{}", stat.class),
+                stat.contended,
+                stat.acquisitions,
+                stat.spins_max,
+                stat.spins_total,
+                stat.wait_max,
+                stat.wait_total,
+                stat.hold_max,
+                stat.hold_total,
+                stat.hold_total / stat.acquisitions.max(1)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Waiting of an acquisition, measured with the `lock-stat` feature.
+#[derive(Debug)]
+pub(crate) struct Wait {
+    /// When the lock was first found contended.
+    #[cfg(feature = "lock-stat")]
+    start: Option<u64>,
+
+    /// Spin iterations while contended.
+    #[cfg(feature = "lock-stat")]
+    spins: usize,
+}
+
+impl Wait {
+    /// Starts an acquisition, uncontended so far.
+    #[inline(always)]
+    pub(crate) const fn new() -> Self {
+        Wait {
+            #[cfg(feature = "lock-stat")]
+            start: None,
+            #[cfg(feature = "lock-stat")]
+            spins: 0,
+        }
+    }
+
+    /// Records that the lock is contended, e.g. before sleeping on it.
+    #[inline(always)]
+    pub(crate) fn contend(&mut self) {
+        #[cfg(feature = "lock-stat")]
+        if self.start.is_none() {
+            self.start = Some(now());
+        }
+    }
+
+    /// Counts a spin iteration waiting for the lock.
+    #[inline(always)]
+    pub(crate) fn spin(&mut self) {
+        #[cfg(feature = "lock-stat")]
+        {
+            self.contend();
+            self.spins += 1;
+        }
+    }
+}
+
+/// A lock being held, whose holding time is recorded when dropped.
+#[derive(Debug)]
+pub(crate) struct Hold {
+    #[cfg(feature = "lock-stat")]
+    stat: Option<(&'static ClassStat, u64)>,
+}
+
+impl Hold {
+    /// A lock whose holding time is not recorded.
+    #[inline(always)]
+    pub(crate) const fn none() -> Self {
+        Hold {
+            #[cfg(feature = "lock-stat")]
+            stat: None,
+        }
+    }
+}
+
+#[cfg(feature = "lock-stat")]
+impl Drop for Hold {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if let Some((stat, start)) = self.stat.take() {
+            let hold = since(start);
+            stat.hold_total.fetch_add(hold, Ordering::Relaxed);
+            stat.hold_max.fetch_max(hold, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Records an acquisition of a lock of `class` after `wait`, returning the token measuring its
+/// holding time.
+#[inline(always)]
+pub(crate) fn acquired(class: &LockClass, wait: Wait) -> Hold {
+    #[cfg(feature = "lock-stat")]
+    if let Some(stat) = class.key().and_then(ClassStat::get) {
+        stat.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if let Some(start) = wait.start {
+            let waited = since(start);
+            stat.contended.fetch_add(1, Ordering::Relaxed);
+            stat.spins_total.fetch_add(wait.spins, Ordering::Relaxed);
+            stat.spins_max.fetch_max(wait.spins, Ordering::Relaxed);
+            stat.wait_total.fetch_add(waited, Ordering::Relaxed);
+            stat.wait_max.fetch_max(waited, Ordering::Relaxed);
+        }
+        return Hold {
+            stat: Some((stat, now())),
+        };
+    }
+    Hold::none()
+}
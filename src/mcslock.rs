@@ -0,0 +1,385 @@
+//! An MCS queue-based spinning mutex.
+//!
+//! Waiting threads are linked into a queue, and each of them spins on the `locked` flag of its
+//! own queue node instead of the shared lock word. Releasing the lock touches only the successor's
+//! node, so the cache-line traffic of a handoff stays constant regardless of the number of waiters.
+//!
+//! Queue nodes are taken from a per-CPU pool, so no memory is allocated when locking.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use crate::{
+    cpu_id,
+    irq::{IrqPolicy, IrqSave, IrqUsage},
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+    NCPU,
+};
+
+/// Maximum number of [`McsLock`]s a CPU can hold or wait for at the same time.
+const MCS_NODES_PER_CPU: usize = 4;
+
+/// A queue node which a waiter spins on, aligned to a cache line to avoid false sharing.
+#[repr(align(64))]
+struct McsNode {
+    /// The next waiter in the queue.
+    next: AtomicPtr<McsNode>,
+
+    /// Whether the owner of this node is still waiting for the lock.
+    locked: AtomicBool,
+
+    /// Whether this node has been claimed by a waiter or holder.
+    in_use: AtomicBool,
+}
+
+impl McsNode {
+    const fn new() -> Self {
+        McsNode {
+            next: AtomicPtr::new(null_mut()),
+            locked: AtomicBool::new(false),
+            in_use: AtomicBool::new(false),
+        }
+    }
+
+    /// Claims a free queue node, preferring the pool of the current CPU.
+    ///
+    /// Nodes of other CPUs are only used if the local pool is exhausted, e.g. on hosted targets
+    /// where every thread reports the same CPU id.
+    fn claim() -> &'static McsNode {
+        let start = cpu_id() * MCS_NODES_PER_CPU;
+        (0..MCS_NODES.len())
+            .map(|i| &MCS_NODES[(start + i) % MCS_NODES.len()])
+            .find(|node| {
+                node.in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .expect("Too many MCS lock nodes in use")
+    }
+
+    /// Returns this node back to the pool.
+    fn release(&self) {
+        self.in_use.store(false, Ordering::Release);
+    }
+}
+
+/// Per-CPU pool of [`McsNode`]s.
+static MCS_NODES: [McsNode; NCPU * MCS_NODES_PER_CPU] =
+    [const { McsNode::new() }; NCPU * MCS_NODES_PER_CPU];
+
+/// An [MCS lock](https://lwn.net/Articles/590243/) providing mutually exclusive access to data
+/// with FIFO fairness and local spinning.
+///
+/// The [`IrqPolicy`] `P` selects what is disabled on the local CPU while the lock is held,
+/// interrupts by default. Under policies leaving interrupts enabled, a preempted holder or waiter
+/// keeps its queue node, so the node pool is exhausted sooner.
+pub struct McsLock<T: ?Sized, P: IrqPolicy = IrqSave> {
+    phantom: PhantomData<P>,
+    /// The last waiter in the queue, or null if the lock is free.
+    tail: AtomicPtr<McsNode>,
+    /// Contexts this lock has been acquired in, checked in debug builds.
+    usage: IrqUsage,
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will hand the lock over to the next waiter.
+pub struct McsLockGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    tail: &'a AtomicPtr<McsNode>,
+    node: &'static McsNode,
+    data: &'a mut T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is handed over, if disabled by this guard.
+    irq: Option<P::Guard>,
+}
+
+// Same unsafe impls as `std::sync::Mutex`
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Sync for McsLock<T, P> {}
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Send for McsLock<T, P> {}
+
+impl<T> McsLock<T> {
+    /// Creates a new [`McsLock`] wrapping the supplied data.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<T, P: IrqPolicy> McsLock<T, P> {
+    /// Creates a new [`McsLock`] with the [`IrqPolicy`] of its type.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn with_policy(data: T) -> Self {
+        McsLock {
+            phantom: PhantomData,
+            tail: AtomicPtr::new(null_mut()),
+            usage: IrqUsage::new(),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`McsLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let McsLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized, P: IrqPolicy> McsLock<T, P> {
+    /// Locks the [`McsLock`] and returns a guard that permits access to the inner data.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is held with interrupts enabled after being acquired
+    /// by [`McsLock::lock_in_irq`].
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock(&self) -> McsLockGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        self.acquire(Some(irq))
+    }
+
+    /// Locks the [`McsLock`] from an interrupt handler, where interrupts are already disabled,
+    /// and returns a guard that permits access to the inner data.
+    ///
+    /// The interrupt state is left untouched regardless of the lock policy.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled elsewhere, which would deadlock once this handler interrupts the holder.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock_in_irq(&self) -> McsLockGuard<'_, T, P> {
+        self.usage.enter_in_irq();
+        self.acquire(None)
+    }
+
+    /// Enqueues a node and spins on it until the lock is handed over.
+    #[inline(always)]
+    #[track_caller]
+    fn acquire(&self, irq: Option<P::Guard>) -> McsLockGuard<'_, T, P> {
+        let held = lockdep::acquire(&self.class, self);
+        let node = McsNode::claim();
+        node.next.store(null_mut(), Ordering::Relaxed);
+        node.locked.store(true, Ordering::Relaxed);
+
+        // Enqueue ourselves at the tail.
+        let node_ptr = node as *const _ as *mut McsNode;
+        let prev = self.tail.swap(node_ptr, Ordering::AcqRel);
+        let mut wait = Wait::new();
+        if !prev.is_null() {
+            // Link behind the previous waiter and spin on our own node.
+            unsafe { &*prev }.next.store(node_ptr, Ordering::Release);
+            while node.locked.load(Ordering::Acquire) {
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+
+        McsLockGuard {
+            tail: &self.tail,
+            node,
+            data: unsafe { &mut *self.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+            irq,
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Try to lock this [`McsLock`], returning a lock guard if successful.
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<McsLockGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        // Back to previous interrupt enabling bit if failed to acquire the lock.
+        self.try_acquire(Some(irq))
+    }
+
+    /// Try to lock this [`McsLock`] from an interrupt handler, returning a lock guard if
+    /// successful. See [`McsLock::lock_in_irq`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock_in_irq(&self) -> Option<McsLockGuard<'_, T, P>> {
+        self.usage.enter_in_irq();
+        self.try_acquire(None)
+    }
+
+    /// Takes the lock with a new node only if no other node is queued.
+    #[inline(always)]
+    #[track_caller]
+    fn try_acquire(&self, irq: Option<P::Guard>) -> Option<McsLockGuard<'_, T, P>> {
+        let node = McsNode::claim();
+        node.next.store(null_mut(), Ordering::Relaxed);
+
+        if self
+            .tail
+            .compare_exchange(
+                null_mut(),
+                node as *const _ as *mut McsNode,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            Some(McsLockGuard {
+                tail: &self.tail,
+                node,
+                data: unsafe { &mut *self.data.get() },
+                held: lockdep::acquired(&self.class, self),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            })
+        } else {
+            node.release();
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`McsLock`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist.
+    /// As such, this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner data.
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Returns a mutable pointer to the underlying data.
+    #[inline(always)]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for McsLock<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "McsLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "McsLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default, P: IrqPolicy> Default for McsLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn default() -> Self {
+        Self::with_policy(Default::default())
+    }
+}
+
+impl<T, P: IrqPolicy> From<T> for McsLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn from(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> McsLockGuard<'a, T, P> {
+    /// Leak the lock guard, yielding a mutable reference to the underlying data.
+    ///
+    /// Note that this function will permanently lock the original [`McsLock`] and never return
+    /// the queue node to the pool.
+    #[inline(always)]
+    pub fn leak(this: Self) -> &'a mut T {
+        let data = this.data as *mut _; // Keep it in pointer form temporarily to avoid double-aliasing
+        core::mem::forget(this);
+        unsafe { &mut *data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for McsLockGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display for McsLockGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for McsLockGuard<'a, T, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> DerefMut for McsLockGuard<'a, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for McsLockGuard<'a, T, P> {
+    /// The dropping of the guard will hand the lock over to the next waiter, or release it.
+    fn drop(&mut self) {
+        let node = self.node;
+        let node_ptr = node as *const _ as *mut McsNode;
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // No known successor, try to release the lock.
+            if self
+                .tail
+                .compare_exchange(node_ptr, null_mut(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                // Back to previous interrupt enabling bit when `irq` is dropped.
+                node.release();
+                return;
+            }
+            // A successor is enqueueing itself, wait until it is linked.
+            loop {
+                next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        unsafe { &*next }.locked.store(false, Ordering::Release);
+        node.release();
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
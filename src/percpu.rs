@@ -0,0 +1,161 @@
+//! Per-CPU variables.
+//!
+//! Each CPU owns a slot of a [`PerCpu`] variable. The slot of the current CPU is accessed with
+//! interrupts disabled, so that neither an interrupt handler nor a task migration can interleave
+//! with the access. Slots of other CPUs can only be read through shared references.
+
+use core::{cell::UnsafeCell, fmt, ops::Deref};
+
+use crate::{
+    cpu_id,
+    irq::{local_irq_save, IrqGuard},
+    ncpu, NCPU,
+};
+
+/// A variable with one instance per CPU.
+///
+/// Values of the current CPU are only accessed through shared references, so use [`Cell`]s,
+/// atomics or locks inside `T` for mutable state.
+///
+/// On hosted targets, threads reporting the same CPU share its slot, and disabling interrupts does
+/// not stop them, so a [`PerCpu`] is only shared between threads if `T` is [`Sync`], e.g. atomics
+/// rather than [`Cell`]s.
+///
+/// ```compile_fail
+/// # use core::cell::Cell;
+/// # use kernel_sync::{PerCpu, NCPU};
+/// static COUNTER: PerCpu<Cell<usize>> = PerCpu::from_array([const { Cell::new(0) }; NCPU]);
+/// ```
+///
+/// [`Cell`]: core::cell::Cell
+pub struct PerCpu<T> {
+    data: UnsafeCell<[T; NCPU]>,
+}
+
+/// A guard that provides immutable access to the value of the current CPU.
+///
+/// Interrupts are disabled until the guard falls out of scope.
+pub struct PerCpuGuard<'a, T> {
+    data: &'a T,
+    /// Interrupts are restored when the guard is dropped.
+    irq: IrqGuard,
+}
+
+// On bare-metal targets, the slot of a CPU is only accessed locally with interrupts disabled, or
+// remotely by shared references to `Sync` values.
+#[cfg(target_os = "none")]
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+// Hosted threads are neither pinned to a CPU nor stopped by disabling interrupts.
+#[cfg(not(target_os = "none"))]
+unsafe impl<T: Send + Sync> Sync for PerCpu<T> {}
+unsafe impl<T: Send> Send for PerCpu<T> {}
+
+impl<T: Copy> PerCpu<T> {
+    /// Creates a new [`PerCpu`] with every CPU holding a copy of `value`.
+    #[inline(always)]
+    pub const fn new(value: T) -> Self {
+        Self::from_array([value; NCPU])
+    }
+}
+
+impl<T> PerCpu<T> {
+    /// Creates a new [`PerCpu`] from the values of each CPU, e.g.
+    /// `PerCpu::from_array([const { AtomicUsize::new(0) }; NCPU])`.
+    #[inline(always)]
+    pub const fn from_array(data: [T; NCPU]) -> Self {
+        PerCpu {
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Returns a guard that permits access to the value of the current CPU, disabling interrupts
+    /// until it is dropped.
+    #[inline(always)]
+    pub fn get(&self) -> PerCpuGuard<'_, T> {
+        // Disable interrrupts to stay on this CPU.
+        let irq = local_irq_save();
+        PerCpuGuard {
+            data: unsafe { self.current_unchecked() },
+            irq,
+        }
+    }
+
+    /// Calls `f` with the value of the current CPU, disabling interrupts during the call.
+    #[inline(always)]
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        f(&self.get())
+    }
+
+    /// Returns the value of the current CPU without disabling interrupts.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts must be disabled, or the caller must otherwise guarantee that it is not
+    /// preempted or migrated while the reference is alive.
+    #[inline(always)]
+    pub unsafe fn current_unchecked(&self) -> &T {
+        &*(self.data.get() as *const T).add(cpu_id())
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// Returns the value of CPU `id`, which can be accessed concurrently by its owner.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than [`NCPU`].
+    #[inline(always)]
+    pub fn remote(&self, id: usize) -> &T {
+        assert!(id < NCPU, "CPU id {} out of range", id);
+        unsafe { &*(self.data.get() as *const T).add(id) }
+    }
+
+    /// Calls `f` with the id and value of every CPU registered by [`init`](crate::init).
+    #[inline(always)]
+    pub fn for_each_cpu<F>(&self, mut f: F)
+    where
+        F: FnMut(usize, &T),
+    {
+        (0..ncpu()).for_each(|id| f(id, self.remote(id)));
+    }
+
+    /// Returns `true` if `f` returns `true` for the value of every CPU registered by
+    /// [`init`](crate::init).
+    #[inline(always)]
+    pub fn all<F>(&self, mut f: F) -> bool
+    where
+        F: FnMut(&T) -> bool,
+    {
+        (0..ncpu()).all(|id| f(self.remote(id)))
+    }
+}
+
+impl<T: Default> Default for PerCpu<T> {
+    fn default() -> Self {
+        Self::from_array(core::array::from_fn(|_| T::default()))
+    }
+}
+
+impl<T: Sync + fmt::Debug> fmt::Debug for PerCpu<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries((0..ncpu()).map(|id| self.remote(id)))
+            .finish()
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for PerCpuGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T> Deref for PerCpuGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
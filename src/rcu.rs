@@ -5,7 +5,7 @@ use core::{
     mem::{align_of, size_of, ManuallyDrop},
     ops::{Deref, DerefMut},
     ptr::NonNull,
-    sync::atomic,
+    sync::atomic::{self, AtomicUsize, Ordering},
 };
 
 use alloc::{
@@ -14,7 +14,10 @@ use alloc::{
     vec::Vec,
 };
 
-use crate::{SeqLock, SpinLock};
+use crate::{
+    arch::{distinct_cpu_ids, smp_mb},
+    pop_off, push_off, CPUs, SeqLock, SpinLock,
+};
 
 /// Changes a [`RcuType`] to a thin pointer.
 ///
@@ -30,10 +33,11 @@ fn rcu_into<T: RcuType>(value: T) -> usize {
     const USIZE_SIZE: usize = size_of::<usize>();
     let v = unsafe {
         match size_of::<T>() {
+            USIZE_SIZE => transmute_copy::<T, usize>(&value),
             1 => transmute_copy::<T, u8>(&value) as usize,
             2 => transmute_copy::<T, u16>(&value) as usize,
+            #[cfg(target_pointer_width = "64")]
             4 => transmute_copy::<T, u32>(&value) as usize,
-            USIZE_SIZE => transmute_copy::<T, usize>(&value),
             size => unreachable!("Unsupported size: {}", size),
         }
     };
@@ -50,10 +54,11 @@ fn rcu_from<T: RcuType>(value: usize) -> T {
     const USIZE_SIZE: usize = size_of::<usize>();
     unsafe {
         match size_of::<T>() {
+            USIZE_SIZE => transmute_copy(&value),
             1 => transmute_copy(&(value as u8)),
             2 => transmute_copy(&(value as u16)),
+            #[cfg(target_pointer_width = "64")]
             4 => transmute_copy(&(value as u32)),
-            USIZE_SIZE => transmute_copy(&value),
             size => unreachable!("Unsupported size: {}", size),
         }
     }
@@ -73,6 +78,10 @@ impl Drop for RcuDrop {
 impl RcuDrop {
     /// This function calls the handler directly. Thus the space taken by this [`RcuDrop`]
     /// is meaningless and we just ignore it.
+    ///
+    /// # Safety
+    ///
+    /// No reader may still hold a reference to the data, i.e. a grace period must have elapsed.
     #[inline(always)]
     pub unsafe fn release(self) {
         self.1(self.0);
@@ -80,37 +89,145 @@ impl RcuDrop {
     }
 }
 
-pub type RcuDropFn = fn(RcuDrop);
+/// Number of the latest grace period that has been started.
+static RCU_GP: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of the latest grace period after which every CPU has passed a quiescent state.
+static RCU_COMPLETED: AtomicUsize = AtomicUsize::new(0);
 
-/// Global drop function for [`RcuDrop`], which should be provided before using [`RcuType`] types.
-static mut RCU_DROP_FN: Option<RcuDropFn> = None;
+/// Pending [`RcuDrop`]s tagged with the grace period they must wait for.
+static RCU_CALLBACKS: SpinLock<Vec<(usize, RcuDrop)>> = SpinLock::untracked(Vec::new());
 
-/// Global pending [`RcuDrop`]s.
-static RCU_DROP_PENDING: SpinLock<Vec<RcuDrop>> = SpinLock::new(Vec::new());
+/// Marks the beginning of an RCU read-side critical section.
+///
+/// Interrupts are disabled until the matching [`rcu_read_unlock`], so the reader cannot be
+/// preempted or migrated. Read-side critical sections can be nested, but must never sleep.
+#[inline]
+pub fn rcu_read_lock() {
+    push_off();
+    let cpu = unsafe { CPUs.current_unchecked() };
+    cpu.rcu_nesting.fetch_add(1, Ordering::SeqCst);
+}
 
-/// Global reclamation to handle pending [`RcuDrop`]s with drop function provided,
-pub unsafe fn reclamation(rcu_drop_fn: RcuDropFn) {
-    RCU_DROP_FN.replace(rcu_drop_fn);
-    let v = core::mem::take(&mut *RCU_DROP_PENDING.lock());
-    v.into_iter().for_each(rcu_drop_fn);
+/// Marks the end of an RCU read-side critical section.
+///
+/// Leaving the outermost critical section is a quiescent state of this CPU, which is recorded
+/// for the updaters waiting in [`synchronize_rcu`].
+#[inline]
+pub fn rcu_read_unlock() {
+    let cpu = unsafe { CPUs.current_unchecked() };
+    // Record the quiescent state before leaving, so that updaters never observe a zero nesting
+    // depth together with a stale grace period.
+    if cpu.rcu_nesting.load(Ordering::SeqCst) == 1 {
+        cpu.rcu_qs
+            .fetch_max(RCU_GP.load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+    let old = cpu.rcu_nesting.fetch_sub(1, Ordering::SeqCst);
+    assert!(old >= 1, "rcu_read_unlock() without rcu_read_lock()");
+    pop_off();
+}
+
+/// Returns `true` if every CPU has passed a quiescent state since grace period `gp` started.
+///
+/// A CPU outside any read-side critical section is quiescent by definition.
+fn rcu_gp_done(gp: usize) -> bool {
+    smp_mb();
+    CPUs.all(|cpu| {
+        cpu.rcu_nesting.load(Ordering::SeqCst) == 0 || cpu.rcu_qs.load(Ordering::SeqCst) >= gp
+    })
 }
 
-/// Clears the handler and waits for next [`reclamation`].
-pub unsafe fn wait() {
-    RCU_DROP_FN.take();
+/// Starts a new grace period, returning its number.
+#[inline]
+fn rcu_gp_start() -> usize {
+    // Removal must be visible to readers before they are checked.
+    smp_mb();
+    RCU_GP.fetch_add(1, Ordering::SeqCst) + 1
 }
 
-/// Drops [`RcuType`] types manually, calling the [`RCU_DROP_FN`] immediately if a
+/// Records that grace period `gp` (and all earlier ones) has completed.
+#[inline]
+fn rcu_gp_complete(gp: usize) {
+    RCU_COMPLETED.fetch_max(gp, Ordering::SeqCst);
+    smp_mb();
+}
+
+/// Waits until all pre-existing RCU read-side critical sections have completed, then invokes
+/// the [`RcuDrop`] callbacks whose grace period has elapsed.
+///
+/// # Panics
+///
+/// Panics if called inside an RCU read-side critical section of the current CPU, where the grace
+/// period would never end. Not checked on hosted targets without [`Arch`](crate::arch::Arch)
+/// backend, where all threads report the same CPU.
+pub fn synchronize_rcu() {
+    assert!(
+        !distinct_cpu_ids() || CPUs.with(|cpu| cpu.rcu_nesting.load(Ordering::SeqCst)) == 0,
+        "synchronize_rcu() inside an RCU read-side critical section"
+    );
+    let gp = rcu_gp_start();
+    while !rcu_gp_done(gp) {
+        core::hint::spin_loop();
+    }
+    rcu_gp_complete(gp);
+    reclamation();
+}
+
+/// Registers an [`RcuDrop`] that will be released after all RCU read-side critical sections
+/// existing at the time of the call have completed.
+///
+/// This function never blocks. Callbacks are invoked by [`synchronize_rcu`] or [`reclamation`].
+pub fn call_rcu(rcu_drop: RcuDrop) {
+    // The removal must be ordered before the snapshot, so that any grace period starting after
+    // this point covers all readers that may hold the removed data.
+    smp_mb();
+    let gp = RCU_GP.load(Ordering::SeqCst) + 1;
+    RCU_CALLBACKS.lock().push((gp, rcu_drop));
+}
+
+/// Global reclamation to release pending [`RcuDrop`]s whose grace period has completed.
+///
+/// This function never blocks: it starts a new grace period if some callbacks are waiting for
+/// one, checks whether the current grace period has completed, and releases the callbacks that
+/// are safe to be released. Kernels are expected to call this periodically, e.g. on timer ticks
+/// or in the idle loop.
+pub fn reclamation() {
+    let mut callbacks = RCU_CALLBACKS.lock();
+    if let Some(needed) = callbacks.iter().map(|(gp, _)| *gp).max() {
+        if RCU_GP.load(Ordering::SeqCst) < needed {
+            rcu_gp_start();
+        }
+    }
+    let gp = RCU_GP.load(Ordering::SeqCst);
+    if RCU_COMPLETED.load(Ordering::SeqCst) < gp && rcu_gp_done(gp) {
+        rcu_gp_complete(gp);
+    }
+
+    let completed = RCU_COMPLETED.load(Ordering::SeqCst);
+    let mut ready = Vec::new();
+    let mut i = 0;
+    while i < callbacks.len() {
+        if callbacks[i].0 <= completed {
+            ready.push(callbacks.swap_remove(i).1);
+        } else {
+            i += 1;
+        }
+    }
+    // Callbacks may take other locks, so release them without holding the pending list.
+    drop(callbacks);
+    ready
+        .into_iter()
+        .for_each(|rcu_drop| unsafe { rcu_drop.release() });
+}
+
+/// Drops [`RcuType`] types after a grace period, queueing them with [`call_rcu`].
 #[inline]
 fn rcu_drop<T: RcuType>(x: T) {
     if !core::mem::needs_drop::<T>() {
         return;
     }
 
-    match unsafe { RCU_DROP_FN } {
-        Some(drop_fn) => unsafe { drop_fn(x.transmute()) },
-        None => RCU_DROP_PENDING.lock().push(unsafe { x.transmute() }),
-    }
+    call_rcu(unsafe { x.transmute() });
 }
 
 /// Types implementing [`RcuType`] must be able to load or store just by one instruction.
@@ -121,15 +238,15 @@ fn rcu_drop<T: RcuType>(x: T) {
 ///
 /// The basic idea behind RCU is to split updates into **removal** and **reclamation** phases:
 /// - **removal**: The removal phase removes references to data items within a data structure
-/// (possibly by replacing them with references to new versions of these data items), and can
-/// run concurrently with readers. The reason that it is safe to run the removal phase concurrently
-/// with readers is the semantics of modern CPUs guarantee that readers will see either the old or
-/// the new version of the data structure rather than a partially updated reference.
-/// In this implementation, [`core::ptr::replace`] satisfies the need perfectly.
+///   (possibly by replacing them with references to new versions of these data items), and can
+///   run concurrently with readers. The reason that it is safe to run the removal phase concurrently
+///   with readers is the semantics of modern CPUs guarantee that readers will see either the old or
+///   the new version of the data structure rather than a partially updated reference.
+///   In this implementation, [`core::ptr::replace`] satisfies the need perfectly.
 /// - **reclamation**: The reclamation phase does the work of reclaiming (e.g., freeing) the data
-/// items removed from the data structure during the removal phase. Because reclaiming data items
-/// can disrupt any readers concurrently referencing those data items, the reclamation phase must
-/// not start until readers no longer hold references to those data items.
+///   items removed from the data structure during the removal phase. Because reclaiming data items
+///   can disrupt any readers concurrently referencing those data items, the reclamation phase must
+///   not start until readers no longer hold references to those data items.
 pub trait RcuType: Sized + 'static {
     /// Checks the type before using RCU.
     #[inline(always)]
@@ -147,6 +264,10 @@ pub trait RcuType: Sized + 'static {
     }
 
     /// Transmute the ownership to [`RcuDrop`].
+    ///
+    /// # Safety
+    ///
+    /// The returned [`RcuDrop`] must be released exactly once, otherwise the data is leaked.
     #[must_use]
     #[inline(always)]
     unsafe fn transmute(self) -> RcuDrop {
@@ -154,31 +275,24 @@ pub trait RcuType: Sized + 'static {
     }
 
     /// Read that precedes any write which follows it in program order returns a guard.
+    ///
+    /// The guard stays in an RCU read-side critical section until it is dropped.
     #[must_use]
     #[inline(always)]
-    fn read(&self) -> RcuReadGuard<Self> {
-        Self::check();
-
-        // Inhibits compiler from automatically calling T's destructor.
-        // Volatile operations are intended to act on I/O memory, and are guaranteed to not
-        // be elided or reordered by the compiler across other volatile operations.
-        let data = unsafe { core::mem::ManuallyDrop::new(core::ptr::read_volatile(self)) };
-
-        // An acquire fence prevents the memory reordering of any read which precedes it in program order
-        // with any read or write which follows it in program order, usually used after a read.
-        atomic::fence(atomic::Ordering::Acquire);
-
-        RcuReadGuard {
-            data,
-            _mark: PhantomData,
-        }
+    fn read(&self) -> RcuReadGuard<'_, Self> {
+        unsafe { read_raw(self) }
     }
 
+    /// Replaces the value at `this`, reclaiming the old one after a grace period.
+    ///
+    /// Writers must not go through a shared reference, which the compiler assumes immutable.
+    ///
     /// # Safety
     ///
-    /// This function provides no synchronization guarantees and should be protected by a lock.
+    /// `this` must be valid for reads and writes, e.g. from [`SyncUnsafeCell::get`]. This function
+    /// provides no synchronization guarantees and should be protected by a lock.
     #[inline]
-    unsafe fn write(&self, new: Self) {
+    unsafe fn write(this: *mut Self, new: Self) {
         Self::check();
 
         // A release fence prevents the memory reordering of any read or write which precedes it
@@ -186,15 +300,19 @@ pub trait RcuType: Sized + 'static {
         atomic::fence(atomic::Ordering::Release);
 
         // removal phase
-        let old = core::ptr::replace(self as *const _ as *mut Self, new);
+        let old = core::ptr::replace(this, new);
 
         // reclamation phase
         old.reclamation();
     }
 
     /// Atomic write without extra synchronization considerations.
+    ///
+    /// # Safety
+    ///
+    /// `this` must be valid for reads and writes, e.g. from [`SyncUnsafeCell::get`].
     #[inline]
-    fn write_atomic(&self, src: Self) {
+    unsafe fn write_atomic(this: *mut Self, src: Self) {
         Self::check();
 
         unsafe {
@@ -205,8 +323,8 @@ pub trait RcuType: Sized + 'static {
             macro_rules! atomic_swap_impl {
                 ($at: ident, $ut: ty) => {{
                     use core::sync::atomic::$at;
-                    core::mem::transmute::<_, &$at>(self)
-                        .swap(new as $ut, atomic::Ordering::Release) as usize
+                    $at::from_ptr(this as *mut $ut).swap(new as $ut, atomic::Ordering::Release)
+                        as usize
                 }};
             }
 
@@ -215,6 +333,7 @@ pub trait RcuType: Sized + 'static {
                 1 => atomic_swap_impl!(AtomicU8, u8),
                 2 => atomic_swap_impl!(AtomicU16, u16),
                 4 => atomic_swap_impl!(AtomicU32, u32),
+                #[cfg(target_has_atomic = "64")]
                 8 => atomic_swap_impl!(AtomicU64, u64),
                 _ => panic!(),
             };
@@ -225,6 +344,34 @@ pub trait RcuType: Sized + 'static {
     }
 }
 
+/// Reads the value at `ptr` in an RCU read-side critical section, which lasts until the returned
+/// guard is dropped.
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads during `'a`, and only written by [`RcuType::write`] or
+/// [`RcuType::write_atomic`].
+#[inline(always)]
+unsafe fn read_raw<'a, T: RcuType>(ptr: *const T) -> RcuReadGuard<'a, T> {
+    T::check();
+
+    rcu_read_lock();
+
+    // Inhibits compiler from automatically calling T's destructor.
+    // Volatile operations are intended to act on I/O memory, and are guaranteed to not
+    // be elided or reordered by the compiler across other volatile operations.
+    let data = unsafe { ManuallyDrop::new(core::ptr::read_volatile(ptr)) };
+
+    // An acquire fence prevents the memory reordering of any read which precedes it in program order
+    // with any read or write which follows it in program order, usually used after a read.
+    atomic::fence(atomic::Ordering::Acquire);
+
+    RcuReadGuard {
+        data,
+        _mark: PhantomData,
+    }
+}
+
 impl<T: 'static> RcuType for *const T {}
 impl<T: 'static> RcuType for *mut T {}
 impl<T: 'static> RcuType for NonNull<T> {}
@@ -275,6 +422,13 @@ impl<'a, T: RcuType> Deref for RcuReadGuard<'a, T> {
     }
 }
 
+impl<'a, T: RcuType> Drop for RcuReadGuard<'a, T> {
+    /// The dropping of the guard will leave the RCU read-side critical section.
+    fn drop(&mut self) {
+        rcu_read_unlock();
+    }
+}
+
 /// Inner [`RcuType`] wrapped with [`SyncUnsafeCell`].
 pub struct RcuCell<T: RcuType>(SyncUnsafeCell<T>);
 
@@ -301,8 +455,9 @@ impl<T: RcuType> RcuCell<T> {
     ///
     /// Avoid holding multiple guards at the same time, for the data may be different.
     #[inline(always)]
-    pub fn read(&self) -> RcuReadGuard<T> {
-        unsafe { &*self.0.get() }.read()
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        // No reference to the data is created, since writers replace it concurrently.
+        unsafe { read_raw(self.0.get()) }
     }
 
     /// # Safety
@@ -310,12 +465,12 @@ impl<T: RcuType> RcuCell<T> {
     /// This function provides no synchronization guarantees and should be protected by a lock.
     #[inline]
     pub unsafe fn write(&self, src: T) {
-        (&*self.0.get()).write(src)
+        T::write(self.0.get(), src)
     }
 
     /// Atomic write without extra synchronization considerations.
     #[inline]
     pub fn write_atomic(&self, src: T) {
-        unsafe { (&*self.0.get()).write_atomic(src) }
+        unsafe { T::write_atomic(self.0.get(), src) }
     }
 }
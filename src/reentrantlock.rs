@@ -0,0 +1,265 @@
+//! A reentrant spinning mutex.
+//!
+//! The lock records the CPU holding it and a recursion counter, so the holder may acquire it again,
+//! e.g. a console lock taken by a panic handler interrupting a print. Interrupts stay disabled while
+//! the lock is held, so no other thread on the holding CPU can enter it.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::Deref,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::distinct_cpu_ids,
+    cpu_id,
+    irq::{local_irq_save, IrqGuard},
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+};
+
+/// No CPU holds the lock.
+const NO_OWNER: usize = usize::MAX;
+
+/// A [`SpinLock`](crate::SpinLock) which the holding CPU may acquire again.
+///
+/// Nested guards alias the data, so they only provide shared access. Use a
+/// [`Cell`](core::cell::Cell) or [`RefCell`](core::cell::RefCell) for mutable data.
+///
+/// Ownership is tracked by CPU id, so on hosted targets an [`Arch`](crate::arch::Arch) backend
+/// telling threads apart must be registered before locking.
+pub struct ReentrantSpinLock<T: ?Sized> {
+    /// CPU holding this lock, or [`NO_OWNER`].
+    owner: AtomicUsize,
+    /// Number of guards of the holding CPU, only accessed by it.
+    count: UnsafeCell<usize>,
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides shared data access.
+///
+/// When the last guard of the holding CPU falls out of scope it will release the lock.
+pub struct ReentrantSpinLockGuard<'a, T: ?Sized + 'a> {
+    lock: &'a ReentrantSpinLock<T>,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released.
+    irq: IrqGuard,
+}
+
+// Same unsafe impls as `std::sync::ReentrantLock`
+unsafe impl<T: ?Sized + Send> Sync for ReentrantSpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for ReentrantSpinLock<T> {}
+
+impl<T> ReentrantSpinLock<T> {
+    /// Creates a new [`ReentrantSpinLock`] wrapping the supplied data.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
+        ReentrantSpinLock {
+            owner: AtomicUsize::new(NO_OWNER),
+            count: UnsafeCell::new(0),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`ReentrantSpinLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let ReentrantSpinLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized> ReentrantSpinLock<T> {
+    /// Locks the [`ReentrantSpinLock`] and returns a guard that permits access to the inner data,
+    /// immediately if the current CPU already holds it.
+    ///
+    /// # Panics
+    ///
+    /// Panics on hosted targets if no [`Arch`](crate::arch::Arch) backend is registered, since all
+    /// threads would share CPU 0 and enter the lock together.
+    ///
+    /// With the `lockdep` feature, panics if the outermost acquisition inverts an order observed
+    /// before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock(&self) -> ReentrantSpinLockGuard<'_, T> {
+        // Disable interrupts to avoid deadlock, and to pin this CPU to the current thread.
+        let irq = local_irq_save();
+        let cpu = Self::holder_id();
+        if self.owner.load(Ordering::Relaxed) == cpu {
+            return self.nested(irq);
+        }
+
+        let held = lockdep::acquire(&self.class, self);
+        let mut wait = Wait::new();
+        while self
+            .owner
+            .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Wait until the lock looks unlocked before retrying
+            while self.is_locked() {
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+        self.outermost(held, lockstat::acquired(&self.class, wait), irq)
+    }
+
+    /// Try to lock this [`ReentrantSpinLock`], returning a lock guard if it is free or already
+    /// held by the current CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics on hosted targets if no [`Arch`](crate::arch::Arch) backend is registered, like
+    /// [`ReentrantSpinLock::lock`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<ReentrantSpinLockGuard<'_, T>> {
+        // Disable interrupts to avoid deadlock, and to pin this CPU to the current thread.
+        let irq = local_irq_save();
+        let cpu = Self::holder_id();
+        if self.owner.load(Ordering::Relaxed) == cpu {
+            Some(self.nested(irq))
+        } else if self
+            .owner
+            .compare_exchange(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(self.outermost(
+                lockdep::acquired(&self.class, self),
+                lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            ))
+        } else {
+            // Failed to acquire the lock, back to previous interrupt enabling bit.
+            None
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) != NO_OWNER
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`ReentrantSpinLock`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist.
+    /// As such, this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner data.
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Returns the id of the current CPU, which identifies the holder of the lock.
+    #[inline(always)]
+    #[track_caller]
+    fn holder_id() -> usize {
+        assert!(
+            distinct_cpu_ids(),
+            "ReentrantSpinLock used on a hosted target without an `Arch` backend telling threads apart"
+        );
+        cpu_id()
+    }
+
+    /// Creates the first guard of the current CPU, which has just acquired the lock.
+    #[inline(always)]
+    fn outermost(&self, held: Held, stat: Hold, irq: IrqGuard) -> ReentrantSpinLockGuard<'_, T> {
+        unsafe { *self.count.get() = 1 };
+        ReentrantSpinLockGuard {
+            lock: self,
+            held,
+            stat,
+            irq,
+        }
+    }
+
+    /// Creates another guard of the current CPU, which holds the lock.
+    #[inline(always)]
+    fn nested(&self, irq: IrqGuard) -> ReentrantSpinLockGuard<'_, T> {
+        let count = unsafe { &mut *self.count.get() };
+        *count = count
+            .checked_add(1)
+            .expect("ReentrantSpinLock count overflow");
+        ReentrantSpinLockGuard {
+            lock: self,
+            held: Held::none(),
+            stat: Hold::none(),
+            irq,
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ReentrantSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "ReentrantSpinLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "ReentrantSpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default> Default for ReentrantSpinLock<T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T> From<T> for ReentrantSpinLock<T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for ReentrantSpinLockGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display> fmt::Display for ReentrantSpinLockGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for ReentrantSpinLockGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for ReentrantSpinLockGuard<'a, T> {
+    /// The dropping of the last guard of the holding CPU will release the lock.
+    fn drop(&mut self) {
+        let count = unsafe { &mut *self.lock.count.get() };
+        *count -= 1;
+        if *count == 0 {
+            self.lock.owner.store(NO_OWNER, Ordering::Release);
+        }
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
@@ -0,0 +1,754 @@
+//! A naive reader-writer spin lock.
+//!
+//! Any number of readers may hold the lock at the same time, while a writer requires exclusive
+//! access. An upgradeable reader coexists with plain readers but blocks new readers and writers,
+//! so it can later be upgraded to a writer without releasing the lock.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    irq::{IrqPolicy, IrqSave, IrqUsage},
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+};
+
+const READER: usize = 1 << 2;
+const UPGRADED: usize = 1 << 1;
+const WRITER: usize = 1;
+
+/// A reader-writer [spin lock](https://en.m.wikipedia.org/wiki/Readers%E2%80%93writer_lock)
+/// allowing many readers or at most one writer at any point in time.
+///
+/// The [`IrqPolicy`] `P` selects what is disabled on the local CPU while the lock is held,
+/// interrupts by default.
+pub struct RwSpinLock<T: ?Sized, P: IrqPolicy = IrqSave> {
+    phantom: PhantomData<P>,
+    lock: AtomicUsize,
+    /// Contexts this lock has been acquired in, checked in debug builds.
+    usage: IrqUsage,
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides immutable data access.
+///
+/// When the guard falls out of scope it will decrement the read count, potentially releasing the lock.
+pub struct RwSpinLockReadGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    lock: &'a AtomicUsize,
+    data: &'a T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released, if disabled by this guard.
+    irq: Option<P::Guard>,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct RwSpinLockWriteGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    inner: &'a RwSpinLock<T, P>,
+    data: &'a mut T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released, if disabled by this guard.
+    irq: Option<P::Guard>,
+}
+
+/// A guard that provides immutable data access but can be upgraded to [`RwSpinLockWriteGuard`].
+///
+/// No writers or other upgradeable guards can exist while this is in scope. New reader creation
+/// is prevented as well to alleviate writer starvation.
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct RwSpinLockUpgradableGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    inner: &'a RwSpinLock<T, P>,
+    data: &'a T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released, if disabled by this guard.
+    irq: Option<P::Guard>,
+}
+
+// Same unsafe impls as `std::sync::RwLock`
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Send for RwSpinLock<T, P> {}
+unsafe impl<T: ?Sized + Send + Sync, P: IrqPolicy> Sync for RwSpinLock<T, P> {}
+
+impl<T> RwSpinLock<T> {
+    /// Creates a new [`RwSpinLock`] wrapping the supplied data.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<T, P: IrqPolicy> RwSpinLock<T, P> {
+    /// Creates a new [`RwSpinLock`] with the [`IrqPolicy`] of its type.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn with_policy(data: T) -> Self {
+        RwSpinLock {
+            phantom: PhantomData,
+            lock: AtomicUsize::new(0),
+            usage: IrqUsage::new(),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`RwSpinLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let RwSpinLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized, P: IrqPolicy> RwSpinLock<T, P> {
+    /// Locks this [`RwSpinLock`] with shared read access, spinning until it can be acquired.
+    ///
+    /// The calling thread will spin until there are no writers or upgradeable readers holding
+    /// the lock. There may be other readers currently inside the lock when this method returns.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is held with interrupts enabled after being acquired
+    /// in an interrupt handler, e.g. by [`RwSpinLock::read_in_irq`].
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before. Readers are validated like writers.
+    #[inline(always)]
+    #[track_caller]
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        self.acquire_read(Some(irq))
+    }
+
+    /// Locks this [`RwSpinLock`] with shared read access from an interrupt handler, where
+    /// interrupts are already disabled, leaving the interrupt state untouched.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled elsewhere, which would deadlock once this handler interrupts a writer.
+    #[inline(always)]
+    #[track_caller]
+    pub fn read_in_irq(&self) -> RwSpinLockReadGuard<'_, T, P> {
+        self.usage.enter_in_irq();
+        self.acquire_read(None)
+    }
+
+    /// Spins until shared read access is acquired.
+    #[inline(always)]
+    #[track_caller]
+    fn acquire_read(&self, irq: Option<P::Guard>) -> RwSpinLockReadGuard<'_, T, P> {
+        let held = lockdep::acquire(&self.class, self);
+        let mut wait = Wait::new();
+        while self.acquire_reader() & (WRITER | UPGRADED) != 0 {
+            // Lock is taken, undo.
+            self.lock.fetch_sub(READER, Ordering::Release);
+            // Wait until the lock looks available before retrying
+            while self.lock.load(Ordering::Relaxed) & (WRITER | UPGRADED) != 0 {
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+
+        RwSpinLockReadGuard {
+            lock: &self.lock,
+            data: unsafe { &*self.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+            irq,
+        }
+    }
+
+    /// Tries to lock this [`RwSpinLock`] with shared read access, returning a guard if successful.
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        // Back to previous interrupt enabling bit if failed to acquire the lock.
+        self.try_acquire_read(Some(irq))
+    }
+
+    /// Tries to lock this [`RwSpinLock`] with shared read access from an interrupt handler,
+    /// returning a guard if successful. See [`RwSpinLock::read_in_irq`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_read_in_irq(&self) -> Option<RwSpinLockReadGuard<'_, T, P>> {
+        self.usage.enter_in_irq();
+        self.try_acquire_read(None)
+    }
+
+    /// Tries to acquire shared read access once.
+    #[inline(always)]
+    #[track_caller]
+    fn try_acquire_read(&self, irq: Option<P::Guard>) -> Option<RwSpinLockReadGuard<'_, T, P>> {
+        let value = self.acquire_reader();
+
+        // We check the UPGRADED bit here so that new readers are prevented when an UPGRADED lock
+        // is held. This helps reduce writer starvation.
+        if value & (WRITER | UPGRADED) != 0 {
+            // Lock is taken, undo.
+            self.lock.fetch_sub(READER, Ordering::Release);
+            None
+        } else {
+            Some(RwSpinLockReadGuard {
+                lock: &self.lock,
+                data: unsafe { &*self.data.get() },
+                held: lockdep::acquired(&self.class, self),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            })
+        }
+    }
+
+    /// Locks this [`RwSpinLock`] with exclusive write access, spinning until it can be acquired.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is held with interrupts enabled after being acquired
+    /// in an interrupt handler, e.g. by [`RwSpinLock::write_in_irq`].
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        self.acquire_write(Some(irq))
+    }
+
+    /// Locks this [`RwSpinLock`] with exclusive write access from an interrupt handler, where
+    /// interrupts are already disabled, leaving the interrupt state untouched.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled elsewhere, which would deadlock once this handler interrupts a holder.
+    #[inline(always)]
+    #[track_caller]
+    pub fn write_in_irq(&self) -> RwSpinLockWriteGuard<'_, T, P> {
+        self.usage.enter_in_irq();
+        self.acquire_write(None)
+    }
+
+    /// Spins until exclusive write access is acquired.
+    #[inline(always)]
+    #[track_caller]
+    fn acquire_write(&self, irq: Option<P::Guard>) -> RwSpinLockWriteGuard<'_, T, P> {
+        let held = lockdep::acquire(&self.class, self);
+        let mut wait = Wait::new();
+        // Can fail to lock even if the lock is not locked. May be more efficient than `try_write`
+        // when called in a loop.
+        while self
+            .lock
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Wait until the lock looks unlocked before retrying
+            while self.is_locked() {
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+
+        RwSpinLockWriteGuard {
+            inner: self,
+            data: unsafe { &mut *self.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+            irq,
+        }
+    }
+
+    /// Tries to lock this [`RwSpinLock`] with exclusive write access, returning a guard if successful.
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        // Back to previous interrupt enabling bit if failed to acquire the lock.
+        self.try_acquire_write(Some(irq))
+    }
+
+    /// Tries to lock this [`RwSpinLock`] with exclusive write access from an interrupt handler,
+    /// returning a guard if successful. See [`RwSpinLock::write_in_irq`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_write_in_irq(&self) -> Option<RwSpinLockWriteGuard<'_, T, P>> {
+        self.usage.enter_in_irq();
+        self.try_acquire_write(None)
+    }
+
+    /// Tries to acquire exclusive write access once.
+    #[inline(always)]
+    #[track_caller]
+    fn try_acquire_write(&self, irq: Option<P::Guard>) -> Option<RwSpinLockWriteGuard<'_, T, P>> {
+        if self
+            .lock
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(RwSpinLockWriteGuard {
+                inner: self,
+                data: unsafe { &mut *self.data.get() },
+                held: lockdep::acquired(&self.class, self),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Obtains an upgradeable reader lock, spinning until it can be acquired.
+    ///
+    /// Upgradeable readers can be upgraded to writers with [`RwSpinLockUpgradableGuard::upgrade`].
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is held with interrupts enabled after being acquired
+    /// in an interrupt handler.
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn upgradeable_read(&self) -> RwSpinLockUpgradableGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = Some(P::enter());
+        self.usage.enter();
+        let held = lockdep::acquire(&self.class, self);
+        let mut wait = Wait::new();
+        while self.lock.fetch_or(UPGRADED, Ordering::Acquire) & (WRITER | UPGRADED) != 0 {
+            // Wait until there is no writer or upgradeable reader before retrying
+            while self.lock.load(Ordering::Relaxed) & (WRITER | UPGRADED) != 0 {
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+
+        RwSpinLockUpgradableGuard {
+            inner: self,
+            data: unsafe { &*self.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+            irq,
+        }
+    }
+
+    /// Tries to obtain an upgradeable reader lock, returning a guard if successful.
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_upgradeable_read(&self) -> Option<RwSpinLockUpgradableGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = Some(P::enter());
+        self.usage.enter();
+        if self.lock.fetch_or(UPGRADED, Ordering::Acquire) & (WRITER | UPGRADED) == 0 {
+            Some(RwSpinLockUpgradableGuard {
+                inner: self,
+                data: unsafe { &*self.data.get() },
+                held: lockdep::acquired(&self.class, self),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            })
+        } else {
+            // We can't unflip the UPGRADED bit back just yet as there is another upgradeable or
+            // write lock. When they unlock, they will clear the bit.
+            // Back to previous interrupt enabling bit.
+            None
+        }
+    }
+
+    /// Returns the number of readers that currently hold the lock (including upgradeable readers).
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn reader_count(&self) -> usize {
+        let state = self.lock.load(Ordering::Relaxed);
+        state / READER + (state & UPGRADED) / UPGRADED
+    }
+
+    /// Returns `true` if the lock is currently held by any reader or writer.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        self.lock.load(Ordering::Relaxed) != 0
+    }
+
+    /// Force decrement the reader count.
+    ///
+    /// # Safety
+    ///
+    /// This is *extremely* unsafe if there are outstanding [`RwSpinLockReadGuard`]s live, or if
+    /// called more times than [`RwSpinLock::read`] has been called, but can be useful in FFI contexts
+    /// where the caller doesn't know how to deal with RAII. Locks acquired in interrupt handlers
+    /// must not be force unlocked.
+    #[inline(always)]
+    pub unsafe fn force_read_decrement(&self) {
+        debug_assert!(self.lock.load(Ordering::Relaxed) & !WRITER > 0);
+        lockdep::release(self);
+        self.lock.fetch_sub(READER, Ordering::Release);
+        // Back to previous interrupt enabling bit.
+        P::force_exit();
+    }
+
+    /// Force unlock exclusive write access.
+    ///
+    /// # Safety
+    ///
+    /// This is *extremely* unsafe if there are outstanding [`RwSpinLockWriteGuard`]s live, or if
+    /// called when there are current readers, but can be useful in FFI contexts where the caller
+    /// doesn't know how to deal with RAII. Locks acquired in interrupt handlers must not be force
+    /// unlocked.
+    #[inline(always)]
+    pub unsafe fn force_write_unlock(&self) {
+        debug_assert_eq!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED), 0);
+        lockdep::release(self);
+        self.lock.fetch_and(!(WRITER | UPGRADED), Ordering::Release);
+        // Back to previous interrupt enabling bit.
+        P::force_exit();
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`RwSpinLock`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist.
+    /// As such, this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner data.
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Returns a mutable pointer to the underlying data.
+    #[inline(always)]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+
+    /// Increases the reader count, returning the previous lock state.
+    #[inline(always)]
+    fn acquire_reader(&self) -> usize {
+        // An arbitrary cap that allows us to catch overflows long before they happen
+        const MAX_READERS: usize = usize::MAX / READER / 2;
+
+        let value = self.lock.fetch_add(READER, Ordering::Acquire);
+        if value > MAX_READERS * READER {
+            self.lock.fetch_sub(READER, Ordering::Relaxed);
+            panic!("Too many lock readers, cannot safely proceed");
+        }
+        value
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for RwSpinLock<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwSpinLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "RwSpinLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default, P: IrqPolicy> Default for RwSpinLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn default() -> Self {
+        Self::with_policy(Default::default())
+    }
+}
+
+impl<T, P: IrqPolicy> From<T> for RwSpinLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn from(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> RwSpinLockReadGuard<'a, T, P> {
+    /// Leak the lock guard, yielding a reference to the underlying data.
+    ///
+    /// Note that this function will permanently lock the original [`RwSpinLock`] for writing.
+    #[inline(always)]
+    pub fn leak(this: Self) -> &'a T {
+        let data = this.data;
+        core::mem::forget(this);
+        data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> RwSpinLockWriteGuard<'a, T, P> {
+    /// Downgrades the writable lock guard to a readable one, releasing write access without
+    /// letting another writer in.
+    #[inline(always)]
+    pub fn downgrade(self) -> RwSpinLockReadGuard<'a, T, P> {
+        // Reserve the read guard for ourselves
+        self.inner.acquire_reader();
+
+        let inner = self.inner;
+        let data = self.data as *const T;
+        // Interrupts stay disabled: the read guard inherits this guard's `held`, `stat`
+        // and `irq`.
+        let held = unsafe { core::ptr::read(&self.held) };
+        let stat = unsafe { core::ptr::read(&self.stat) };
+        let irq = unsafe { core::ptr::read(&self.irq) };
+        core::mem::forget(self);
+        inner
+            .lock
+            .fetch_and(!(WRITER | UPGRADED), Ordering::Release);
+
+        RwSpinLockReadGuard {
+            lock: &inner.lock,
+            data: unsafe { &*data },
+            held,
+            stat,
+            irq,
+        }
+    }
+
+    /// Downgrades the writable lock guard to an upgradeable one, without letting another writer in.
+    #[inline(always)]
+    pub fn downgrade_to_upgradeable(self) -> RwSpinLockUpgradableGuard<'a, T, P> {
+        // A waiting upgradeable reader may have set the UPGRADED bit already.
+        debug_assert_eq!(self.inner.lock.load(Ordering::Acquire) & WRITER, WRITER);
+
+        let inner = self.inner;
+        let data = self.data as *const T;
+        // Interrupts stay disabled: the upgradeable guard inherits this guard's `held`, `stat`
+        // and `irq`.
+        let held = unsafe { core::ptr::read(&self.held) };
+        let stat = unsafe { core::ptr::read(&self.stat) };
+        let irq = unsafe { core::ptr::read(&self.irq) };
+        core::mem::forget(self);
+        // Keep the temporary reader counts of concurrent `try_read` calls, which are undone later,
+        // and the UPGRADED bit set by a waiting upgradeable reader, which keeps waiting.
+        inner.lock.fetch_or(UPGRADED, Ordering::Acquire);
+        inner.lock.fetch_and(!WRITER, Ordering::Release);
+
+        RwSpinLockUpgradableGuard {
+            inner,
+            data: unsafe { &*data },
+            held,
+            stat,
+            irq,
+        }
+    }
+
+    /// Leak the lock guard, yielding a mutable reference to the underlying data.
+    ///
+    /// Note that this function will permanently lock the original [`RwSpinLock`].
+    #[inline(always)]
+    pub fn leak(this: Self) -> &'a mut T {
+        let data = this.data as *mut _; // Keep it in pointer form temporarily to avoid double-aliasing
+        core::mem::forget(this);
+        unsafe { &mut *data }
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> RwSpinLockUpgradableGuard<'a, T, P> {
+    /// Upgrades an upgradeable lock guard to a writable lock guard, spinning until all readers
+    /// have left.
+    #[inline(always)]
+    pub fn upgrade(mut self) -> RwSpinLockWriteGuard<'a, T, P> {
+        loop {
+            self = match self.try_upgrade() {
+                Ok(guard) => return guard,
+                Err(e) => e,
+            };
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tries to upgrade an upgradeable lock guard to a writable lock guard, returning the
+    /// original guard if there are still readers holding the lock.
+    #[inline(always)]
+    pub fn try_upgrade(self) -> Result<RwSpinLockWriteGuard<'a, T, P>, Self> {
+        if self
+            .inner
+            .lock
+            .compare_exchange(UPGRADED, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            let inner = self.inner;
+            // Interrupts stay disabled: the write guard inherits this guard's `held`, `stat`
+            // and `irq`.
+            let held = unsafe { core::ptr::read(&self.held) };
+            let stat = unsafe { core::ptr::read(&self.stat) };
+            let irq = unsafe { core::ptr::read(&self.irq) };
+            core::mem::forget(self);
+
+            Ok(RwSpinLockWriteGuard {
+                inner,
+                data: unsafe { &mut *inner.data.get() },
+                held,
+                stat,
+                irq,
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Downgrades the upgradeable lock guard to a readable, shared lock guard. Cannot fail and is
+    /// guaranteed not to spin.
+    #[inline(always)]
+    pub fn downgrade(self) -> RwSpinLockReadGuard<'a, T, P> {
+        // Reserve the read guard for ourselves
+        self.inner.acquire_reader();
+
+        let inner = self.inner;
+        let data = self.data;
+        // Interrupts stay disabled: the read guard inherits this guard's `held`, `stat`
+        // and `irq`.
+        let held = unsafe { core::ptr::read(&self.held) };
+        let stat = unsafe { core::ptr::read(&self.stat) };
+        let irq = unsafe { core::ptr::read(&self.irq) };
+        core::mem::forget(self);
+        inner.lock.fetch_sub(UPGRADED, Ordering::AcqRel);
+
+        RwSpinLockReadGuard {
+            lock: &inner.lock,
+            data,
+            held,
+            stat,
+            irq,
+        }
+    }
+
+    /// Leak the lock guard, yielding a reference to the underlying data.
+    ///
+    /// Note that this function will permanently lock the original [`RwSpinLock`].
+    #[inline(always)]
+    pub fn leak(this: Self) -> &'a T {
+        let data = this.data;
+        core::mem::forget(this);
+        data
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for RwSpinLockReadGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display for RwSpinLockReadGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for RwSpinLockWriteGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display for RwSpinLockWriteGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for RwSpinLockUpgradableGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display
+    for RwSpinLockUpgradableGuard<'a, T, P>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for RwSpinLockReadGuard<'a, T, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for RwSpinLockUpgradableGuard<'a, T, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for RwSpinLockWriteGuard<'a, T, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> DerefMut for RwSpinLockWriteGuard<'a, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for RwSpinLockReadGuard<'a, T, P> {
+    /// The dropping of the read guard will decrease the reader count.
+    fn drop(&mut self) {
+        debug_assert!(self.lock.load(Ordering::Relaxed) & !(WRITER | UPGRADED) > 0);
+        self.lock.fetch_sub(READER, Ordering::Release);
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for RwSpinLockUpgradableGuard<'a, T, P> {
+    /// The dropping of the upgradeable guard will release the upgraded bit.
+    fn drop(&mut self) {
+        debug_assert_eq!(
+            self.inner.lock.load(Ordering::Relaxed) & (WRITER | UPGRADED),
+            UPGRADED
+        );
+        self.inner.lock.fetch_sub(UPGRADED, Ordering::AcqRel);
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for RwSpinLockWriteGuard<'a, T, P> {
+    /// The dropping of the write guard will release the lock it was created from.
+    fn drop(&mut self) {
+        debug_assert_eq!(self.inner.lock.load(Ordering::Relaxed) & WRITER, WRITER);
+        // Writer is responsible for clearing both WRITER and UPGRADED bits.
+        // The UPGRADED bit may be set if an upgradeable lock attempts an upgrade while this lock is held.
+        self.inner
+            .lock
+            .fetch_and(!(WRITER | UPGRADED), Ordering::Release);
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
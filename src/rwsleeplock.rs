@@ -0,0 +1,333 @@
+//! A naive sleeping reader-writer lock.
+//!
+//! Like [`SleepLock`](crate::SleepLock), waiting threads yield the CPU instead of spinning, which
+//! suits critical sections that may block on I/O. Any number of readers may hold the lock at the
+//! same time, while a writer requires exclusive access. New readers sleep as long as a writer is
+//! waiting, so writers never starve.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    id::LockId,
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+    sleeplock::Sched,
+    spinlock::SpinLock,
+    waitqueue::{sleep, WaitQueue},
+};
+
+/// A sleep lock allowing many readers or at most one writer, yielding the CPU when locked.
+pub struct RwSleepLock<T: ?Sized, S: Sched> {
+    phantom: PhantomData<S>,
+
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+
+    /// [`SpinLock`] protecting this [`RwSleepLock`].
+    inner: SpinLock<RwSleepLockInner<T, S>>,
+}
+
+/// Inner info protected by lock.
+pub struct RwSleepLockInner<T: ?Sized, S: Sched> {
+    phantom: PhantomData<S>,
+
+    /// A unique identifier of this [`RwSleepLock`].
+    id: LockId,
+
+    /// Number of readers holding this lock.
+    readers: usize,
+
+    /// If a writer holds this lock.
+    writer: bool,
+
+    /// Number of writers waiting for this lock, which blocks new readers.
+    waiting_writers: usize,
+
+    /// Readers sleeping on this lock.
+    read_waiters: WaitQueue<S>,
+
+    /// Writers sleeping on this lock.
+    write_waiters: WaitQueue<S>,
+
+    /// Data of this lock.
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides immutable data access.
+///
+/// When the guard falls out of scope it will decrement the read count, potentially releasing the lock.
+pub struct RwSleepLockReadGuard<'a, T: ?Sized + 'a, S: Sched> {
+    phantom: PhantomData<S>,
+    lock: &'a SpinLock<RwSleepLockInner<T, S>>,
+    data: &'a T,
+    held: Held,
+    stat: Hold,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct RwSleepLockWriteGuard<'a, T: ?Sized + 'a, S: Sched> {
+    phantom: PhantomData<S>,
+    lock: &'a SpinLock<RwSleepLockInner<T, S>>,
+    data: &'a mut T,
+    held: Held,
+    stat: Hold,
+}
+
+// unsafe thread-safe impls
+unsafe impl<T: ?Sized + Send, S: Sched> Send for RwSleepLock<T, S> {}
+unsafe impl<T: ?Sized + Send + Sync, S: Sched> Sync for RwSleepLock<T, S> {}
+
+impl<T, S: Sched> RwSleepLock<T, S> {
+    /// Creates a new [`RwSleepLock`] wrapping the supplied data.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
+        RwSleepLock {
+            phantom: PhantomData,
+            class: LockClass::new(),
+            inner: SpinLock::untracked(RwSleepLockInner {
+                phantom: PhantomData,
+                id: LockId::new(),
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+                read_waiters: WaitQueue::new(),
+                write_waiters: WaitQueue::new(),
+                data: UnsafeCell::new(data),
+            }),
+        }
+    }
+
+    /// Consumes this [`RwSleepLock`] and unwraps the underlying data.
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let RwSleepLock { inner, .. } = self;
+        inner.into_inner().data.into_inner()
+    }
+}
+
+impl<T: ?Sized, S: Sched> RwSleepLock<T, S> {
+    /// Locks this [`RwSleepLock`] with shared read access, sleeping until it can be acquired.
+    ///
+    /// The calling thread sleeps while a writer holds the lock or is waiting for it.
+    ///
+    /// # Panics
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while `thread` holds other sleep
+    /// locks inverts an order observed before. Readers are validated like writers.
+    #[inline(always)]
+    #[track_caller]
+    pub fn read(&self, thread: &SpinLock<S>) -> RwSleepLockReadGuard<'_, T, S> {
+        let held = lockdep::acquire_sleep(&self.class, self, thread);
+        let mut inner = self.inner.lock();
+        let mut wait = Wait::new();
+
+        // Automatically release the lock and sleep on chan.
+        while inner.writer || inner.waiting_writers > 0 {
+            let lock_id = inner.id.get();
+            wait.contend();
+            inner = sleep(inner, |inner| &mut inner.read_waiters, lock_id, thread);
+        }
+        inner.readers += 1;
+
+        RwSleepLockReadGuard {
+            phantom: PhantomData,
+            lock: &self.inner,
+            data: unsafe { &*inner.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+        }
+    }
+
+    /// Locks this [`RwSleepLock`] with exclusive write access, sleeping until it can be acquired.
+    ///
+    /// # Panics
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while `thread` holds other sleep
+    /// locks inverts an order observed before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn write(&self, thread: &SpinLock<S>) -> RwSleepLockWriteGuard<'_, T, S> {
+        let held = lockdep::acquire_sleep(&self.class, self, thread);
+        let mut inner = self.inner.lock();
+        let mut wait = Wait::new();
+
+        // Block new readers until we get the lock.
+        inner.waiting_writers += 1;
+        // Automatically release the lock and sleep on chan.
+        while inner.writer || inner.readers > 0 {
+            let lock_id = inner.id.get();
+            wait.contend();
+            inner = sleep(inner, |inner| &mut inner.write_waiters, lock_id, thread);
+        }
+        inner.waiting_writers -= 1;
+        inner.writer = true;
+
+        RwSleepLockWriteGuard {
+            phantom: PhantomData,
+            lock: &self.inner,
+            data: unsafe { &mut *inner.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+        }
+    }
+
+    /// Tries to lock this [`RwSleepLock`] with shared read access, returning a guard if successful.
+    #[inline(always)]
+    pub fn try_read(&self) -> Option<RwSleepLockReadGuard<'_, T, S>> {
+        let mut inner = self.inner.lock();
+        if !inner.writer && inner.waiting_writers == 0 {
+            inner.readers += 1;
+            Some(RwSleepLockReadGuard {
+                phantom: PhantomData,
+                lock: &self.inner,
+                data: unsafe { &*inner.data.get() },
+                held: Held::none(),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Tries to lock this [`RwSleepLock`] with exclusive write access, returning a guard if successful.
+    #[inline(always)]
+    pub fn try_write(&self) -> Option<RwSleepLockWriteGuard<'_, T, S>> {
+        let mut inner = self.inner.lock();
+        if !inner.writer && inner.readers == 0 {
+            inner.writer = true;
+            Some(RwSleepLockWriteGuard {
+                phantom: PhantomData,
+                lock: &self.inner,
+                data: unsafe { &mut *inner.data.get() },
+                held: Held::none(),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of readers that currently hold the lock.
+    #[inline(always)]
+    pub fn reader_count(&self) -> usize {
+        self.inner.lock().readers
+    }
+
+    /// Returns `true` if the lock is currently held by any reader or writer.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        let inner = self.inner.lock();
+        inner.writer || inner.readers > 0
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`RwSleepLock`] mutably, and a mutable reference is guaranteed to be exclusive in Rust,
+    /// no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist. As such,
+    /// this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner data.
+        self.inner.get_mut().data.get_mut()
+    }
+
+    /// Returns a mutable pointer to the underlying data.
+    #[inline(always)]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        unsafe { (*self.inner.as_mut_ptr()).data.get() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, S: Sched> fmt::Debug for RwSleepLock<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => write!(f, "RwSleepLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "RwSleepLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default, S: Sched> Default for RwSleepLock<T, S> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl<T, S: Sched> From<T> for RwSleepLock<T, S> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn from(data: T) -> Self {
+        Self::new(data)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, S: Sched> fmt::Debug for RwSleepLockReadGuard<'a, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, S: Sched> fmt::Debug for RwSleepLockWriteGuard<'a, T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, S: Sched> Deref for RwSleepLockReadGuard<'a, T, S> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, S: Sched> Deref for RwSleepLockWriteGuard<'a, T, S> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, S: Sched> DerefMut for RwSleepLockWriteGuard<'a, T, S> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, S: Sched> Drop for RwSleepLockReadGuard<'a, T, S> {
+    /// The dropping of the read guard will decrease the reader count, waking up a sleeping
+    /// writer when the last reader leaves.
+    fn drop(&mut self) {
+        let mut inner = self.lock.lock();
+        inner.readers -= 1;
+        if inner.readers == 0 {
+            // Readers only sleep behind writers, which will wake them up in turn.
+            // Must be called without any thread lock.
+            inner.write_waiters.wake_one();
+        }
+    }
+}
+
+impl<'a, T: ?Sized, S: Sched> Drop for RwSleepLockWriteGuard<'a, T, S> {
+    /// The dropping of the write guard will release the lock it was created from.
+    fn drop(&mut self) {
+        let mut inner = self.lock.lock();
+        inner.writer = false;
+        // Writers take precedence, otherwise all readers can enter together.
+        // Must be called without any thread lock.
+        if !inner.write_waiters.wake_one() {
+            inner.read_waiters.wake_all();
+        }
+    }
+}
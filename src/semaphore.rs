@@ -0,0 +1,113 @@
+//! A naive counting semaphore.
+//!
+//! A semaphore guards a pool of identical resources. Threads taking a resource from an empty
+//! pool yield the CPU through the same [`Sched`] hooks as [`SleepLock`](crate::SleepLock),
+//! until another thread puts resources back.
+
+use core::{fmt, marker::PhantomData};
+
+use crate::{
+    id::LockId,
+    sleeplock::Sched,
+    spinlock::SpinLock,
+    waitqueue::{sleep, WaitQueue},
+};
+
+/// A [counting semaphore](https://en.wikipedia.org/wiki/Semaphore_(programming)) whose waiters sleep.
+pub struct Semaphore<S: Sched> {
+    phantom: PhantomData<S>,
+
+    /// [`SpinLock`] protecting this [`Semaphore`].
+    inner: SpinLock<SemaphoreInner<S>>,
+}
+
+/// Inner info protected by lock.
+struct SemaphoreInner<S: Sched> {
+    /// A unique identifier of this [`Semaphore`].
+    id: LockId,
+
+    /// Number of available resources.
+    count: usize,
+
+    /// Threads sleeping on this [`Semaphore`].
+    waiters: WaitQueue<S>,
+}
+
+impl<S: Sched> Semaphore<S> {
+    /// Creates a new [`Semaphore`] with `count` available resources.
+    #[inline(always)]
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            phantom: PhantomData,
+            inner: SpinLock::untracked(SemaphoreInner {
+                id: LockId::new(),
+                count,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    /// Acquires a resource, sleeping until one is available.
+    #[inline(always)]
+    pub fn down(&self, thread: &SpinLock<S>) {
+        let mut inner = self.inner.lock();
+
+        // Automatically release the lock and sleep on chan.
+        while inner.count == 0 {
+            let id = inner.id.get();
+            inner = sleep(inner, |inner| &mut inner.waiters, id, thread);
+        }
+        inner.count -= 1;
+    }
+
+    /// Tries to acquire a resource without sleeping, returning `true` if successful.
+    #[inline(always)]
+    pub fn down_trylock(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.count > 0 {
+            inner.count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Releases a resource, waking up a thread sleeping on this [`Semaphore`].
+    #[inline(always)]
+    pub fn up(&self) {
+        self.up_n(1);
+    }
+
+    /// Releases `n` resources at once, waking up to `n` threads sleeping on this [`Semaphore`].
+    #[inline(always)]
+    pub fn up_n(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.count += n;
+        // Must be called without any thread lock.
+        for _ in 0..n {
+            if !inner.waiters.wake_one() {
+                break;
+            }
+        }
+    }
+
+    /// Returns the number of available resources.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn count(&self) -> usize {
+        self.inner.lock().count
+    }
+}
+
+impl<S: Sched> fmt::Debug for Semaphore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Semaphore {{ count: {} }}", self.count())
+    }
+}
@@ -38,6 +38,7 @@ unsafe impl<T: ?Sized + Send> Send for SeqLock<T> {}
 impl<T> SeqLock<T> {
     /// Creates a new [`SeqLock`] wrapping the supplied data.
     #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     pub const fn new(data: T) -> Self {
         Self {
             seq: SyncUnsafeCell::new(0),
@@ -96,7 +97,13 @@ impl<T: ?Sized> SeqLock<T> {
     }
 
     /// Locks the [`SeqLock`] and returns a guard that permits mutable access to inner data.
-    pub fn write(&self) -> SeqLockGuard<T> {
+    ///
+    /// # Panics
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
+    #[track_caller]
+    pub fn write(&self) -> SeqLockGuard<'_, T> {
         let lock = self.lock.lock();
         let seq = unsafe { &mut *self.seq.get() };
 
@@ -165,7 +172,7 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for SeqLock<T> {
     }
 }
 
-impl<T: ?Sized + Default> Default for SeqLock<T> {
+impl<T: Default> Default for SeqLock<T> {
     fn default() -> Self {
         Self::new(Default::default())
     }
@@ -14,13 +14,12 @@ use core::{
     sync::atomic::AtomicBool,
 };
 
-use spin::Lazy;
-
 use crate::{
-    arch::{cpu_id, intr_get},
-    id::RecycleAllocator,
+    id::LockId,
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
     spinlock::{SpinLock, SpinLockGuard},
-    CPUs,
+    waitqueue::{sleep, WaitQueue},
 };
 
 /// Threads should implement this trait to support sleep lock.
@@ -28,23 +27,28 @@ pub trait Sched {
     /// Thread state is changed to `SLEEPING`.
     fn sleep(thread: &mut Self);
 
-    /// Wakeup all threads sleeping on this lock.
-    fn wakeup(id: usize);
+    /// Thread state is changed to `RUNNABLE`, called for a thread dequeued from a [`WaitQueue`].
+    fn wake(thread: &mut Self);
 
     /// Threads acquiring this [`SleepLock`] is grouped by the id.
     fn set_id(thread: &mut Self, id: Option<usize>);
 
     /// Switch to scheduler.
+    ///
+    /// # Safety
+    ///
+    /// Must be called with exactly one level of `push_off()` nesting (the thread lock)
+    /// and interrupts disabled. The thread lock is released after the context switch.
     unsafe fn sched(guard: SpinLockGuard<Self>);
 }
 
-static SleepLockIDAllocator: Lazy<SpinLock<RecycleAllocator>> =
-    Lazy::new(|| SpinLock::new(RecycleAllocator::new(0)));
-
 /// A sleep lock providing mutually exclusive access to data and yielding the CPU when locked.
 pub struct SleepLock<T: ?Sized, S: Sched> {
     phantom: PhantomData<S>,
 
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+
     /// [`SpinLock`] protecting this [`SleepLock`].
     inner: SpinLock<SleepLockInner<T, S>>,
 }
@@ -54,11 +58,14 @@ pub struct SleepLockInner<T: ?Sized, S: Sched> {
     phantom: PhantomData<S>,
 
     /// A unique identifier of this [`SleepLock`].
-    id: usize,
+    id: LockId,
 
     /// If this mutex is locked and holds the inner data.
     locked: bool,
 
+    /// Threads sleeping on this mutex.
+    waiters: WaitQueue<S>,
+
     /// Data of this mutex.
     data: UnsafeCell<T>,
 }
@@ -68,8 +75,10 @@ pub struct SleepLockInner<T: ?Sized, S: Sched> {
 /// When the guard falls out of scope it will release the lock.
 pub struct SleepLockGuard<'a, T: ?Sized + 'a, S: Sched> {
     phantom: PhantomData<S>,
-    lock: &'a SpinLock<SleepLockInner<T, S>>,
+    pub(crate) lock: &'a SleepLock<T, S>,
     data: &'a mut T,
+    held: Held,
+    stat: Hold,
 }
 
 // unsafe thread-safe impls
@@ -79,10 +88,12 @@ unsafe impl<T: ?Sized + Send, S: Sched> Send for SleepLock<T, S> {}
 impl<T, S: Sched> SleepLock<T, S> {
     /// Creates a new [`SleepLock`] wrapping the supplied data.
     #[inline(always)]
-    pub fn new(data: T) -> Self {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
         SleepLock {
             phantom: PhantomData,
-            inner: SpinLock::new(SleepLockInner::new(data)),
+            class: LockClass::new(),
+            inner: SpinLock::untracked(SleepLockInner::new(data)),
         }
     }
 
@@ -107,11 +118,12 @@ impl<T, S: Sched> SleepLock<T, S> {
 impl<T, S: Sched> SleepLockInner<T, S> {
     /// Creates a new [`SleepLockInner`].
     #[inline(always)]
-    pub fn new(data: T) -> Self {
+    pub const fn new(data: T) -> Self {
         SleepLockInner {
             phantom: PhantomData,
-            id: SleepLockIDAllocator.lock().alloc(),
+            id: LockId::new(),
             locked: false,
+            waiters: WaitQueue::new(),
             data: UnsafeCell::new(data),
         }
     }
@@ -139,43 +151,32 @@ impl<T: ?Sized, S: Sched> SleepLock<T, S> {
     ///
     /// The returned value may be dereferenced for data access
     /// and the lock will be dropped when the guard falls out of scope.
+    ///
+    /// # Panics
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while `thread` holds other sleep
+    /// locks inverts an order observed before.
     #[inline(always)]
-    pub fn lock(&self, thread: &SpinLock<S>) -> SleepLockGuard<T, S> {
+    #[track_caller]
+    pub fn lock(&self, thread: &SpinLock<S>) -> SleepLockGuard<'_, T, S> {
+        let held = lockdep::acquire_sleep(&self.class, self, thread);
         let mut inner = self.inner.lock();
-        let lock_id = inner.id;
+        let mut wait = Wait::new();
 
         // Automatically release the lock and sleep on chan.
         while inner.locked {
-            let mut guard = thread.lock();
-            drop(inner);
-
-            S::sleep(&mut guard);
-            S::set_id(&mut guard, Some(lock_id));
-
-            unsafe {
-                // Interrupt cannot be nesting or set before scheduler.
-                assert!(CPUs[cpu_id()].noff == 1);
-                assert!(!intr_get());
-                // Saves and restores CPU local variable, intena.
-                let intena = CPUs[cpu_id()].intena;
-                S::sched(guard);
-                CPUs[cpu_id()].intena = intena;
-            }
-
-            // Tidy up
-            guard = thread.lock();
-            S::set_id(&mut guard, None);
-            drop(guard);
-
-            // Reacquire original lock
-            inner = self.inner.lock();
+            let lock_id = inner.id.get();
+            wait.contend();
+            inner = sleep(inner, |inner| &mut inner.waiters, lock_id, thread);
         }
         inner.locked = true;
 
         SleepLockGuard {
             phantom: PhantomData,
-            lock: &self.inner,
+            lock: self,
             data: unsafe { &mut *inner.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
         }
     }
 
@@ -186,23 +187,32 @@ impl<T: ?Sized, S: Sched> SleepLock<T, S> {
     }
 
     /// Force unlock this [`SleepLock`].
+    ///
+    /// # Safety
+    ///
+    /// This is *extremely* unsafe if the lock is not held by the current thread.
     #[inline(always)]
     pub unsafe fn force_unlock(&self) {
+        lockdep::release_sleep(self);
         let mut inner = self.inner.lock();
         inner.locked = false;
-        S::wakeup(inner.id);
+        inner.waiters.wake_one();
     }
 
     /// Tries to lock this [`SleepLock`], returning a guard if successful.
+    ///
+    /// The lock is not recorded by lockdep, since no thread is given.
     #[inline(always)]
-    pub fn try_lock(&self) -> Option<SleepLockGuard<T, S>> {
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T, S>> {
         let mut inner = self.inner.lock();
         if !inner.locked {
             inner.locked = true;
             Some(SleepLockGuard {
                 phantom: PhantomData,
-                lock: &self.inner,
+                lock: self,
                 data: unsafe { &mut *inner.data.get() },
+                held: Held::none(),
+                stat: lockstat::acquired(&self.class, Wait::new()),
             })
         } else {
             None
@@ -214,20 +224,22 @@ impl<T: ?Sized + fmt::Debug, S: Sched> fmt::Debug for SleepLock<T, S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_lock() {
             Some(guard) => write!(f, "SleepLock {{ data: ")
-                .and_then(|()| (&*guard).fmt(f))
+                .and_then(|()| (*guard).fmt(f))
                 .and_then(|()| write!(f, "}}")),
             None => write!(f, "SleepLock {{ <locked> }}"),
         }
     }
 }
 
-impl<T: ?Sized + Default, S: Sched> Default for SleepLock<T, S> {
+impl<T: Default, S: Sched> Default for SleepLock<T, S> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     fn default() -> Self {
         Self::new(Default::default())
     }
 }
 
 impl<T, S: Sched> From<T> for SleepLock<T, S> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     fn from(data: T) -> Self {
         Self::new(data)
     }
@@ -269,10 +281,10 @@ impl<'a, T: ?Sized, S: Sched> DerefMut for SleepLockGuard<'a, T, S> {
 impl<'a, T: ?Sized, S: Sched> Drop for SleepLockGuard<'a, T, S> {
     /// The dropping of the MutexGuard will release the lock it was created from.
     fn drop(&mut self) {
-        let mut inner = self.lock.lock();
+        let mut inner = self.lock.inner.lock();
         inner.locked = false;
-        // Wake up all processes sleeping on chan.
+        // Wake up the process sleeping for the longest time.
         // Must be called without any thread lock.
-        S::wakeup(inner.id);
+        inner.waiters.wake_one();
     }
 }
@@ -6,36 +6,82 @@
 use core::{
     cell::UnsafeCell,
     fmt,
+    marker::PhantomData,
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicBool, Ordering},
 };
 
-use crate::{pop_off, push_off};
+use crate::{
+    deadlock::{Owner, Spinner},
+    irq::{IrqPolicy, IrqSave, IrqUsage},
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+};
 
 /// A [spin lock](https://en.m.wikipedia.org/wiki/Spinlock) providing mutually exclusive access to data.
-pub struct SpinLock<T: ?Sized> {
+///
+/// The [`IrqPolicy`] `P` selects what is disabled on the local CPU while the lock is held,
+/// interrupts by default.
+pub struct SpinLock<T: ?Sized, P: IrqPolicy = IrqSave> {
+    phantom: PhantomData<P>,
     pub(crate) lock: AtomicBool,
+    /// Contexts this lock has been acquired in, checked in debug builds.
+    usage: IrqUsage,
+    /// Holder of this lock, recorded in debug builds or with the `spin-timeout` feature.
+    owner: Owner,
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
     data: UnsafeCell<T>,
 }
 
 /// A guard that provides mutable data access.
 ///
 /// When the guard falls out of scope it will release the lock.
-pub struct SpinLockGuard<'a, T: ?Sized + 'a> {
-    lock: &'a AtomicBool,
+pub struct SpinLockGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    pub(crate) lock: &'a SpinLock<T, P>,
     data: &'a mut T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released, if disabled by this guard.
+    irq: Option<P::Guard>,
 }
 
 // Same unsafe impls as `std::sync::Mutex`
-unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
-unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Sync for SpinLock<T, P> {}
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Send for SpinLock<T, P> {}
 
 impl<T> SpinLock<T> {
     /// Creates a new [`SpinLock`] wrapping the supplied data.
     #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     pub const fn new(data: T) -> Self {
+        Self::with_policy(data)
+    }
+
+    /// Creates an internal [`SpinLock`] of this crate, which is not validated by lockdep.
+    #[inline(always)]
+    pub(crate) const fn untracked(data: T) -> Self {
+        Self::with_class(data, LockClass::untracked())
+    }
+}
+
+impl<T, P: IrqPolicy> SpinLock<T, P> {
+    /// Creates a new [`SpinLock`] with the [`IrqPolicy`] of its type, e.g.
+    /// `static LOCK: SpinLock<usize, NoIrq> = SpinLock::with_policy(0);`.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn with_policy(data: T) -> Self {
+        Self::with_class(data, LockClass::new())
+    }
+
+    #[inline(always)]
+    const fn with_class(data: T, class: LockClass) -> Self {
         SpinLock {
+            phantom: PhantomData,
             lock: AtomicBool::new(false),
+            usage: IrqUsage::new(),
+            owner: Owner::new(),
+            class,
             data: UnsafeCell::new(data),
         }
     }
@@ -50,29 +96,50 @@ impl<T> SpinLock<T> {
     }
 }
 
-impl<T: ?Sized> SpinLock<T> {
+impl<T: ?Sized, P: IrqPolicy> SpinLock<T, P> {
     /// Locks the [`SpinLock`] and returns a guard that permits access to the inner data.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is already held by the current CPU under a
+    /// non-preemptible policy, which would spin forever, or if the lock is held with interrupts
+    /// enabled after being acquired by [`SpinLock::lock_in_irq`]. Use
+    /// [`ReentrantSpinLock`](crate::ReentrantSpinLock) for nested acquisitions.
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
     #[inline(always)]
-    pub fn lock(&self) -> SpinLockGuard<T> {
-        // Disable interrrupts to avoid deadlock.
-        push_off();
-        // Can fail to lock even if the spinlock is not locked. May be more efficient than `try_lock`
-        // when called in a loop.
-        while self
-            .lock
-            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            // Wait until the lock looks unlocked before retrying
-            while self.is_locked() {
-                core::hint::spin_loop();
-            }
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        if !P::PREEMPTIBLE {
+            self.owner.check();
         }
+        let held = lockdep::acquire(&self.class, self);
+        let stat = self.acquire();
+        self.guard(held, stat, Some(irq))
+    }
 
-        SpinLockGuard {
-            lock: &self.lock,
-            data: unsafe { &mut *self.data.get() },
-        }
+    /// Locks the [`SpinLock`] from an interrupt handler, where interrupts are already disabled,
+    /// and returns a guard that permits access to the inner data.
+    ///
+    /// The interrupt state is left untouched regardless of the lock policy.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled elsewhere, which would deadlock once this handler interrupts the holder.
+    /// Also panics if the interrupted code on this CPU holds the lock.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock_in_irq(&self) -> SpinLockGuard<'_, T, P> {
+        self.usage.enter_in_irq();
+        self.owner.check();
+        let held = lockdep::acquire(&self.class, self);
+        let stat = self.acquire();
+        self.guard(held, stat, None)
     }
 
     /// Returns `true` if the lock is currently held.
@@ -92,34 +159,49 @@ impl<T: ?Sized> SpinLock<T> {
     ///
     /// This is *extremely* unsafe if the lock is not held by the current
     /// thread. However, this can be useful in some instances for exposing the
-    /// lock to FFI that doesn't know how to deal with RAII.
+    /// lock to FFI that doesn't know how to deal with RAII. Locks acquired by
+    /// [`SpinLock::lock_in_irq`] must not be force unlocked.
     #[inline(always)]
     pub unsafe fn force_unlock(&self) {
+        lockdep::release(self);
+        self.owner.clear();
         self.lock.store(false, Ordering::Release);
         // Back to previous interrupt enabling bit.
-        pop_off();
+        P::force_exit();
     }
 
     /// Try to lock this [`Mutex`], returning a lock guard if successful.
     #[inline(always)]
-    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
-        // Disable interrrupts to avoid deadlock.
-        push_off();
-        // The reason for using a strong compare_exchange is explained here:
-        // https://github.com/Amanieu/parking_lot/pull/207#issuecomment-575869107
-        if self
-            .lock
-            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
-        {
-            Some(SpinLockGuard {
-                lock: &self.lock,
-                data: unsafe { &mut *self.data.get() },
-            })
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        if self.try_acquire() {
+            Some(self.guard(
+                lockdep::acquired(&self.class, self),
+                lockstat::acquired(&self.class, Wait::new()),
+                Some(irq),
+            ))
+        } else {
+            // Failed to acquire the lock, back to previous interrupt enabling bit.
+            None
+        }
+    }
+
+    /// Try to lock this [`SpinLock`] from an interrupt handler, returning a lock guard if
+    /// successful. See [`SpinLock::lock_in_irq`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock_in_irq(&self) -> Option<SpinLockGuard<'_, T, P>> {
+        self.usage.enter_in_irq();
+        if self.try_acquire() {
+            Some(self.guard(
+                lockdep::acquired(&self.class, self),
+                lockstat::acquired(&self.class, Wait::new()),
+                None,
+            ))
         } else {
-            // Failed to acquire the lock.
-            // Back to previous interrupt enabling bit.
-            pop_off();
             None
         }
     }
@@ -141,32 +223,91 @@ impl<T: ?Sized> SpinLock<T> {
     pub fn as_mut_ptr(&self) -> *mut T {
         self.data.get()
     }
+
+    /// Spins until the lock is acquired, returning the token measuring its holding time.
+    ///
+    /// # Panics
+    ///
+    /// With the `spin-timeout` feature, panics if the lock is not acquired within the spin timeout
+    /// and no handler is registered, see [`set_spin_timeout`](crate::set_spin_timeout).
+    #[inline(always)]
+    #[cfg_attr(feature = "spin-timeout", track_caller)]
+    fn acquire(&self) -> Hold {
+        let mut spinner = Spinner::new();
+        let mut wait = Wait::new();
+        // Can fail to lock even if the spinlock is not locked. May be more efficient than `try_lock`
+        // when called in a loop.
+        while self
+            .lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // Wait until the lock looks unlocked before retrying
+            while self.is_locked() {
+                spinner.spin(&self.owner);
+                wait.spin();
+                core::hint::spin_loop();
+            }
+        }
+        self.owner.set();
+        lockstat::acquired(&self.class, wait)
+    }
+
+    /// Tries to acquire the lock once, returning `true` if successful.
+    #[inline(always)]
+    #[cfg_attr(feature = "spin-timeout", track_caller)]
+    fn try_acquire(&self) -> bool {
+        // The reason for using a strong compare_exchange is explained here:
+        // https://github.com/Amanieu/parking_lot/pull/207#issuecomment-575869107
+        let acquired = self
+            .lock
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok();
+        if acquired {
+            self.owner.set();
+        }
+        acquired
+    }
+
+    /// Creates a guard of the acquired lock.
+    #[inline(always)]
+    fn guard(&self, held: Held, stat: Hold, irq: Option<P::Guard>) -> SpinLockGuard<'_, T, P> {
+        SpinLockGuard {
+            lock: self,
+            data: unsafe { &mut *self.data.get() },
+            held,
+            stat,
+            irq,
+        }
+    }
 }
 
-impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinLock<T> {
+impl<T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for SpinLock<T, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_lock() {
             Some(guard) => write!(f, "SpinLock {{ data: ")
-                .and_then(|()| (&*guard).fmt(f))
+                .and_then(|()| (*guard).fmt(f))
                 .and_then(|()| write!(f, "}}")),
             None => write!(f, "SpinLock {{ <locked> }}"),
         }
     }
 }
 
-impl<T: ?Sized + Default> Default for SpinLock<T> {
+impl<T: Default, P: IrqPolicy> Default for SpinLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     fn default() -> Self {
-        Self::new(Default::default())
+        Self::with_policy(Default::default())
     }
 }
 
-impl<T> From<T> for SpinLock<T> {
+impl<T, P: IrqPolicy> From<T> for SpinLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
     fn from(data: T) -> Self {
-        Self::new(data)
+        Self::with_policy(data)
     }
 }
 
-impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized, P: IrqPolicy> SpinLockGuard<'a, T, P> {
     /// Leak the lock guard, yielding a mutable reference to the underlying data.
     ///
     /// Note that this function will permanently lock the original [`SpinLock`].
@@ -178,36 +319,36 @@ impl<'a, T: ?Sized> SpinLockGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for SpinLockGuard<'a, T, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<'a, T: ?Sized + fmt::Display> fmt::Display for SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display for SpinLockGuard<'a, T, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&**self, f)
     }
 }
 
-impl<'a, T: ?Sized> Deref for SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for SpinLockGuard<'a, T, P> {
     type Target = T;
     fn deref(&self) -> &T {
         self.data
     }
 }
 
-impl<'a, T: ?Sized> DerefMut for SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized, P: IrqPolicy> DerefMut for SpinLockGuard<'a, T, P> {
     fn deref_mut(&mut self) -> &mut T {
         self.data
     }
 }
 
-impl<'a, T: ?Sized> Drop for SpinLockGuard<'a, T> {
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for SpinLockGuard<'a, T, P> {
     /// The dropping of the MutexGuard will release the lock it was created from.
     fn drop(&mut self) {
-        self.lock.store(false, Ordering::Release);
-        // Back to previous interrupt enabling bit.
-        pop_off();
+        self.lock.owner.clear();
+        self.lock.lock.store(false, Ordering::Release);
+        // Back to previous interrupt enabling bit when `irq` is dropped.
     }
 }
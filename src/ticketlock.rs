@@ -0,0 +1,316 @@
+//! A ticket-based spinning mutex.
+//!
+//! Waiting threads take a ticket and spin until it is served, so the lock is handed over in
+//! FIFO order and no waiter can starve. The price is that every waiter still polls the same
+//! counter, and a preempted waiter blocks all tickets behind it.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    irq::{IrqPolicy, IrqSave, IrqUsage},
+    lockdep::{self, Held, LockClass},
+    lockstat::{self, Hold, Wait},
+};
+
+/// A [ticket lock](https://en.wikipedia.org/wiki/Ticket_lock) providing mutually exclusive
+/// access to data with FIFO fairness.
+///
+/// The [`IrqPolicy`] `P` selects what is disabled on the local CPU while the lock is held,
+/// interrupts by default.
+pub struct TicketLock<T: ?Sized, P: IrqPolicy = IrqSave> {
+    phantom: PhantomData<P>,
+    next_ticket: AtomicUsize,
+    next_serving: AtomicUsize,
+    /// Contexts this lock has been acquired in, checked in debug builds.
+    usage: IrqUsage,
+    /// Class of this lock, validated with the `lockdep` feature.
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+/// A guard that provides mutable data access.
+///
+/// When the guard falls out of scope it will release the lock to the next ticket.
+pub struct TicketLockGuard<'a, T: ?Sized + 'a, P: IrqPolicy = IrqSave> {
+    next_serving: &'a AtomicUsize,
+    ticket: usize,
+    data: &'a mut T,
+    held: Held,
+    stat: Hold,
+    /// Interrupts are restored after the lock is released, if disabled by this guard.
+    irq: Option<P::Guard>,
+}
+
+// Same unsafe impls as `std::sync::Mutex`
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Sync for TicketLock<T, P> {}
+unsafe impl<T: ?Sized + Send, P: IrqPolicy> Send for TicketLock<T, P> {}
+
+impl<T> TicketLock<T> {
+    /// Creates a new [`TicketLock`] wrapping the supplied data.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn new(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<T, P: IrqPolicy> TicketLock<T, P> {
+    /// Creates a new [`TicketLock`] with the [`IrqPolicy`] of its type.
+    #[inline(always)]
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    pub const fn with_policy(data: T) -> Self {
+        TicketLock {
+            phantom: PhantomData,
+            next_ticket: AtomicUsize::new(0),
+            next_serving: AtomicUsize::new(0),
+            usage: IrqUsage::new(),
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Consumes this [`TicketLock`] and unwraps the underlying data.
+    #[inline(always)]
+    pub fn into_inner(self) -> T {
+        // We know statically that there are no outstanding references to
+        // `self` so there's no need to lock.
+        let TicketLock { data, .. } = self;
+        data.into_inner()
+    }
+}
+
+impl<T: ?Sized, P: IrqPolicy> TicketLock<T, P> {
+    /// Locks the [`TicketLock`] and returns a guard that permits access to the inner data.
+    ///
+    /// Threads acquire the lock in the order they called this function.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if the lock is held with interrupts enabled after being acquired
+    /// by [`TicketLock::lock_in_irq`].
+    ///
+    /// With the `lockdep` feature, panics if acquiring the lock while holding others inverts an
+    /// order observed before.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock(&self) -> TicketLockGuard<'_, T, P> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        self.acquire(Some(irq))
+    }
+
+    /// Locks the [`TicketLock`] from an interrupt handler, where interrupts are already disabled,
+    /// and returns a guard that permits access to the inner data.
+    ///
+    /// The interrupt state is left untouched regardless of the lock policy.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, panics if interrupts are enabled, or if the lock has been held with
+    /// interrupts enabled elsewhere, which would deadlock once this handler interrupts the holder.
+    #[inline(always)]
+    #[track_caller]
+    pub fn lock_in_irq(&self) -> TicketLockGuard<'_, T, P> {
+        self.usage.enter_in_irq();
+        self.acquire(None)
+    }
+
+    /// Takes a ticket and spins until it is served.
+    #[inline(always)]
+    #[track_caller]
+    fn acquire(&self, irq: Option<P::Guard>) -> TicketLockGuard<'_, T, P> {
+        let held = lockdep::acquire(&self.class, self);
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+
+        // Wait until our ticket is being served.
+        let mut wait = Wait::new();
+        while self.next_serving.load(Ordering::Acquire) != ticket {
+            wait.spin();
+            core::hint::spin_loop();
+        }
+
+        TicketLockGuard {
+            next_serving: &self.next_serving,
+            ticket,
+            data: unsafe { &mut *self.data.get() },
+            held,
+            stat: lockstat::acquired(&self.class, wait),
+            irq,
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    ///
+    /// # Safety
+    ///
+    /// This function provides no synchronization guarantees and so its result should be considered 'out of date'
+    /// the instant it is called. Do not use it for synchronization purposes. However, it may be useful as a heuristic.
+    #[inline(always)]
+    pub fn is_locked(&self) -> bool {
+        let ticket = self.next_ticket.load(Ordering::Relaxed);
+        self.next_serving.load(Ordering::Relaxed) != ticket
+    }
+
+    /// Force unlock this [`TicketLock`], serving the next ticket.
+    ///
+    /// # Safety
+    ///
+    /// This is *extremely* unsafe if the lock is not held by the current
+    /// thread. However, this can be useful in some instances for exposing the
+    /// lock to FFI that doesn't know how to deal with RAII. Locks acquired by
+    /// [`TicketLock::lock_in_irq`] must not be force unlocked.
+    #[inline(always)]
+    pub unsafe fn force_unlock(&self) {
+        lockdep::release(self);
+        self.next_serving.fetch_add(1, Ordering::Release);
+        // Back to previous interrupt enabling bit.
+        P::force_exit();
+    }
+
+    /// Try to lock this [`TicketLock`], returning a lock guard if successful.
+    ///
+    /// A ticket is only taken if it would be served immediately, so a failed attempt does not
+    /// enqueue the caller.
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<TicketLockGuard<'_, T, P>> {
+        // Disable interrrupts to avoid deadlock, as selected by the policy.
+        let irq = P::enter();
+        self.usage.enter();
+        // Back to previous interrupt enabling bit if failed to acquire the lock.
+        self.try_acquire(Some(irq))
+    }
+
+    /// Try to lock this [`TicketLock`] from an interrupt handler, returning a lock guard if
+    /// successful. See [`TicketLock::lock_in_irq`].
+    #[inline(always)]
+    #[track_caller]
+    pub fn try_lock_in_irq(&self) -> Option<TicketLockGuard<'_, T, P>> {
+        self.usage.enter_in_irq();
+        self.try_acquire(None)
+    }
+
+    /// Takes a ticket only if it would be served immediately.
+    #[inline(always)]
+    #[track_caller]
+    fn try_acquire(&self, irq: Option<P::Guard>) -> Option<TicketLockGuard<'_, T, P>> {
+        let ticket = self.next_serving.load(Ordering::Acquire);
+        if self
+            .next_ticket
+            .compare_exchange(
+                ticket,
+                ticket.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            Some(TicketLockGuard {
+                next_serving: &self.next_serving,
+                ticket,
+                data: unsafe { &mut *self.data.get() },
+                held: lockdep::acquired(&self.class, self),
+                stat: lockstat::acquired(&self.class, Wait::new()),
+                irq,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the [`TicketLock`] mutably, and a mutable reference is guaranteed to be exclusive in
+    /// Rust, no actual locking needs to take place -- the mutable borrow statically guarantees no locks exist.
+    /// As such, this is a 'zero-cost' operation.
+    #[inline(always)]
+    pub fn get_mut(&mut self) -> &mut T {
+        // We know statically that there are no other references to `self`, so
+        // there's no need to lock the inner data.
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Returns a mutable pointer to the underlying data.
+    #[inline(always)]
+    pub fn as_mut_ptr(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for TicketLock<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "TicketLock {{ data: ")
+                .and_then(|()| (*guard).fmt(f))
+                .and_then(|()| write!(f, "}}")),
+            None => write!(f, "TicketLock {{ <locked> }}"),
+        }
+    }
+}
+
+impl<T: Default, P: IrqPolicy> Default for TicketLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn default() -> Self {
+        Self::with_policy(Default::default())
+    }
+}
+
+impl<T, P: IrqPolicy> From<T> for TicketLock<T, P> {
+    #[cfg_attr(any(feature = "lockdep", feature = "lock-stat"), track_caller)]
+    fn from(data: T) -> Self {
+        Self::with_policy(data)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> TicketLockGuard<'a, T, P> {
+    /// Leak the lock guard, yielding a mutable reference to the underlying data.
+    ///
+    /// Note that this function will permanently lock the original [`TicketLock`].
+    #[inline(always)]
+    pub fn leak(this: Self) -> &'a mut T {
+        let data = this.data as *mut _; // Keep it in pointer form temporarily to avoid double-aliasing
+        core::mem::forget(this);
+        unsafe { &mut *data }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug, P: IrqPolicy> fmt::Debug for TicketLockGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Display, P: IrqPolicy> fmt::Display for TicketLockGuard<'a, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Deref for TicketLockGuard<'a, T, P> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> DerefMut for TicketLockGuard<'a, T, P> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.data
+    }
+}
+
+impl<'a, T: ?Sized, P: IrqPolicy> Drop for TicketLockGuard<'a, T, P> {
+    /// The dropping of the guard will serve the next ticket.
+    fn drop(&mut self) {
+        self.next_serving
+            .store(self.ticket.wrapping_add(1), Ordering::Release);
+        // Back to previous interrupt enabling bit when `irq` is dropped.
+    }
+}
@@ -0,0 +1,144 @@
+//! A queue of threads sleeping on a lock.
+//!
+//! Every sleeping primitive embeds a [`WaitQueue`] recording the threads waiting for it, so that
+//! releasing the primitive wakes up exactly the threads it needs to, instead of scanning all
+//! threads in the kernel.
+
+use alloc::collections::VecDeque;
+use core::{fmt, ptr::NonNull};
+
+use crate::{
+    irq::IrqState,
+    sleeplock::Sched,
+    spinlock::{SpinLock, SpinLockGuard},
+};
+
+/// A FIFO queue of threads sleeping on a lock.
+///
+/// A [`WaitQueue`] provides no synchronization itself and must be protected by the lock of the
+/// primitive it is embedded in, which is also the lock released by the sleeping threads.
+pub struct WaitQueue<S: Sched> {
+    waiters: VecDeque<NonNull<SpinLock<S>>>,
+}
+
+// Threads in the queue are only dereferenced while they are blocked, under the protecting lock.
+unsafe impl<S: Sched + Send> Send for WaitQueue<S> {}
+
+impl<S: Sched> WaitQueue<S> {
+    /// Creates a new empty [`WaitQueue`].
+    #[inline(always)]
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    /// Appends `thread` to the end of this queue.
+    ///
+    /// # Safety
+    ///
+    /// `thread` must stay alive until it is woken up by this queue or removed from it.
+    #[inline(always)]
+    pub unsafe fn push(&mut self, thread: &SpinLock<S>) {
+        self.waiters.push_back(NonNull::from(thread));
+    }
+
+    /// Removes `thread` from this queue, returning `true` if it was waiting.
+    #[inline(always)]
+    pub fn remove(&mut self, thread: &SpinLock<S>) -> bool {
+        let thread = NonNull::from(thread);
+        match self.waiters.iter().position(|waiter| *waiter == thread) {
+            Some(i) => {
+                self.waiters.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes up the thread waiting for the longest time, returning `false` if there is none.
+    ///
+    /// Must be called without any thread lock.
+    #[inline(always)]
+    pub fn wake_one(&mut self) -> bool {
+        match self.waiters.pop_front() {
+            Some(thread) => {
+                S::wake(&mut unsafe { thread.as_ref() }.lock());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes up all waiting threads, returning the number of threads woken up.
+    ///
+    /// Must be called without any thread lock.
+    #[inline(always)]
+    pub fn wake_all(&mut self) -> usize {
+        let n = self.waiters.len();
+        while self.wake_one() {}
+        n
+    }
+
+    /// Returns the number of waiting threads.
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Returns `true` if no thread is waiting.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+impl<S: Sched> Default for WaitQueue<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Sched> fmt::Debug for WaitQueue<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WaitQueue {{ len: {} }}", self.len())
+    }
+}
+
+/// Enqueues `thread` to the [`WaitQueue`] selected from `inner`, releases the lock guard `inner`,
+/// puts `thread` to sleep on the lock `id` and switches to the scheduler. Returns the reacquired
+/// guard after the thread has been woken up and scheduled again.
+///
+/// Callers must check their condition again, for other threads might have taken the lock before
+/// this thread runs.
+pub(crate) fn sleep<'a, I: ?Sized, S: Sched>(
+    mut inner: SpinLockGuard<'a, I>,
+    wait_queue: impl Fn(&mut I) -> &mut WaitQueue<S>,
+    id: usize,
+    thread: &SpinLock<S>,
+) -> SpinLockGuard<'a, I> {
+    // The thread stays alive on this stack frame until it is dequeued below.
+    unsafe { wait_queue(&mut inner).push(thread) };
+    let lock = inner.lock;
+
+    let mut guard = thread.lock();
+    drop(inner);
+
+    S::sleep(&mut guard);
+    S::set_id(&mut guard, Some(id));
+
+    // Saves and restores the interrupt state of this thread.
+    let irq = IrqState::save();
+    unsafe { S::sched(guard) };
+    irq.restore();
+
+    // Tidy up
+    guard = thread.lock();
+    S::set_id(&mut guard, None);
+    drop(guard);
+
+    // Reacquire original lock, leaving the queue if woken up by others.
+    let mut inner = lock.lock();
+    wait_queue(&mut inner).remove(thread);
+    inner
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel_sync::{PerCpu, SeqLock, SpinLock, NCPU};
+
+static COUNTER: PerCpu<AtomicUsize> = PerCpu::from_array([const { AtomicUsize::new(0) }; NCPU]);
+
+#[test]
+fn test() {
+    // A backend keeping the CPU id out of any register, in a thread-local variable.
+    common::init();
+
+    let cpu = common::cpu_id();
+    COUNTER.with(|counter| counter.fetch_add(1, Ordering::Relaxed));
+    assert_eq!(COUNTER.remote(cpu).load(Ordering::Relaxed), 1);
+
+    let other = common::on_other_cpu(|| {
+        COUNTER.with(|counter| counter.fetch_add(1, Ordering::Relaxed));
+        common::cpu_id()
+    });
+    assert_ne!(other, cpu);
+    assert_eq!(COUNTER.remote(other).load(Ordering::Relaxed), 1);
+    assert_eq!(COUNTER.remote(cpu).load(Ordering::Relaxed), 1);
+
+    let lock = SpinLock::new(0);
+    *lock.lock() += 1;
+    assert_eq!(*lock.lock(), 1);
+
+    let seq = SeqLock::new(0);
+    *seq.write() = 2;
+    assert_eq!(seq.read(|data| *data), 2);
+}
@@ -0,0 +1,21 @@
+#![cfg(feature = "std")]
+
+use std::{thread, time::Duration};
+
+use kernel_sync::clock::{Clock, DefaultClock};
+
+#[test]
+fn test() {
+    // Hosted targets count nanoseconds.
+    let start = DefaultClock.now();
+    thread::sleep(Duration::from_millis(5));
+    let end = DefaultClock.now();
+    assert!(end - start >= 5_000_000);
+
+    let mut last = end;
+    for _ in 0..1000 {
+        let now = DefaultClock.now();
+        assert!(now >= last);
+        last = now;
+    }
+}
@@ -0,0 +1,171 @@
+#![allow(dead_code)]
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Once,
+    },
+    thread,
+};
+
+use kernel_sync::{
+    arch::{set_arch, Arch},
+    SleepLockSched, SpinLockGuard, NCPU,
+};
+
+/// A hosted thread, parked while sleeping on a lock until another thread wakes it up.
+#[derive(Default)]
+pub struct Thread {
+    /// Number of times this thread has been woken up.
+    pub woken: usize,
+
+    /// Id of the lock this thread last slept on.
+    pub slept_on: Option<usize>,
+
+    /// Whether this thread is sleeping, cleared by the thread waking it up.
+    sleeping: Arc<AtomicBool>,
+
+    /// Handle of the sleeping thread, unparked when woken up.
+    parked: Option<thread::Thread>,
+}
+
+impl Thread {
+    /// Returns `true` if this thread has been put to sleep and not woken up yet.
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping.load(Ordering::Acquire)
+    }
+}
+
+impl SleepLockSched for Thread {
+    fn sleep(thread: &mut Self) {
+        thread.parked = Some(thread::current());
+        thread.sleeping.store(true, Ordering::Release);
+    }
+
+    fn wake(thread: &mut Self) {
+        thread.woken += 1;
+        thread.sleeping.store(false, Ordering::Release);
+        if let Some(parked) = thread.parked.take() {
+            parked.unpark();
+        }
+    }
+
+    fn set_id(thread: &mut Self, id: Option<usize>) {
+        if id.is_some() {
+            thread.slept_on = id;
+        }
+    }
+
+    unsafe fn sched(guard: SpinLockGuard<Self>) {
+        let sleeping = Arc::clone(&guard.sleeping);
+        drop(guard);
+        // An unpark sent before parking makes `park` return immediately.
+        while sleeping.load(Ordering::Acquire) {
+            thread::park();
+        }
+    }
+}
+
+/// CPU ids taken by live threads, one bit per CPU.
+static CPUS_TAKEN: AtomicUsize = AtomicUsize::new(0);
+
+/// The CPU id of a thread, given back when the thread exits.
+struct CpuId(usize);
+
+impl CpuId {
+    fn take() -> Self {
+        let mut taken = CPUS_TAKEN.load(Ordering::Relaxed);
+        loop {
+            let id = (!taken).trailing_zeros() as usize;
+            assert!(id < NCPU, "more than {} threads are taking locks", NCPU);
+            match CPUS_TAKEN.compare_exchange(
+                taken,
+                taken | 1 << id,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return CpuId(id),
+                Err(current) => taken = current,
+            }
+        }
+    }
+}
+
+impl Drop for CpuId {
+    fn drop(&mut self) {
+        CPUS_TAKEN.fetch_and(!(1 << self.0), Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    static CPU_ID: CpuId = CpuId::take();
+
+    /// CPU id taken by [`on_other_cpu`] in place of [`CPU_ID`].
+    static OTHER_CPU_ID: Cell<Option<usize>> = const { Cell::new(None) };
+
+    /// Interrupt enabling bit of the thread, disabled initially.
+    static INTENA: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Returns the CPU id of the current thread.
+pub fn cpu_id() -> usize {
+    OTHER_CPU_ID
+        .with(|id| id.get())
+        .unwrap_or_else(|| CPU_ID.with(|id| id.0))
+}
+
+/// Runs `f` on a CPU of no other thread, e.g. to try a lock held by the current CPU from another
+/// one.
+pub fn on_other_cpu<R>(f: impl FnOnce() -> R) -> R {
+    /// Gives the previous CPU id back to the thread, even if `f` panics.
+    struct Restore(Option<usize>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            OTHER_CPU_ID.with(|id| id.set(self.0));
+        }
+    }
+
+    let other = CpuId::take();
+    let _restore = Restore(OTHER_CPU_ID.with(|id| id.replace(Some(other.0))));
+    f()
+}
+
+/// A backend giving each live thread its own CPU and interrupt enabling bit, so that locks held
+/// by a thread are not mistaken for locks of another one.
+pub struct ThreadArch;
+
+impl Arch for ThreadArch {
+    fn cpu_id(&self) -> usize {
+        cpu_id()
+    }
+
+    fn intr_on(&self) {
+        INTENA.with(|intena| intena.set(true));
+    }
+
+    fn intr_off(&self) {
+        INTENA.with(|intena| intena.set(false));
+    }
+
+    fn intr_get(&self) -> bool {
+        INTENA.with(|intena| intena.get())
+    }
+}
+
+/// Registers [`ThreadArch`], for tests running several threads which take locks.
+///
+/// The backend is registered once per test binary, by the first caller. Every test of a binary
+/// calling this must call it before taking any lock, so that no lock is used while registering.
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe { set_arch(&ThreadArch) });
+}
+
+/// Waits until `thread` has been put to sleep.
+pub fn wait_sleeping(thread: &kernel_sync::SpinLock<Thread>) {
+    while !thread.lock().is_sleeping() {
+        thread::yield_now();
+    }
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use std::thread;
+
+use common::Thread;
+use kernel_sync::{Condvar, SleepLock, SpinLock};
+
+#[test]
+fn test() {
+    common::init();
+    let thread = SpinLock::new(Thread::default());
+    let cond = Condvar::<Thread>::new();
+
+    // The condition already holds, so neither waiter sleeps.
+    let lock = SpinLock::new(1);
+    let guard = cond.wait_while(lock.lock(), &thread, |n| *n == 0);
+    assert_eq!(*guard, 1);
+    drop(guard);
+    assert!(!lock.is_locked());
+
+    let lock = SleepLock::<_, Thread>::new(1);
+    let guard = cond.wait_while(lock.lock(&thread), &thread, |n| *n == 0);
+    assert_eq!(*guard, 1);
+    drop(guard);
+    assert!(!lock.is_locked());
+
+    cond.notify_one();
+    cond.notify_all();
+}
+
+#[test]
+fn notify() {
+    common::init();
+    let cond = Condvar::<Thread>::new();
+    let lock = SpinLock::new(false);
+    let waiter = SpinLock::new(Thread::default());
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let guard = cond.wait_while(lock.lock(), &waiter, |ready| !*ready);
+            assert!(*guard);
+        });
+
+        common::wait_sleeping(&waiter);
+        // The waiter released the lock before sleeping.
+        *lock.lock() = true;
+        cond.notify_one();
+    });
+    assert_eq!(waiter.lock().woken, 1);
+
+    // Sleep locks are released and reacquired on behalf of the waiter.
+    let lock = SleepLock::<_, Thread>::new(0);
+    let waiters: Vec<_> = (0..3).map(|_| SpinLock::new(Thread::default())).collect();
+    thread::scope(|s| {
+        for waiter in &waiters {
+            s.spawn(|| {
+                let mut guard = cond.wait_while(lock.lock(waiter), waiter, |n| *n == 0);
+                *guard += 1;
+            });
+        }
+
+        waiters.iter().for_each(common::wait_sleeping);
+        let main = SpinLock::new(Thread::default());
+        *lock.lock(&main) = 1;
+        cond.notify_all();
+    });
+    assert_eq!(*lock.try_lock().unwrap(), 4);
+    assert!(waiters.iter().all(|waiter| waiter.lock().woken >= 1));
+}
@@ -0,0 +1,16 @@
+use kernel_sync::{init, ncpu, SpinLock, NCPU};
+
+#[test]
+fn test() {
+    assert_eq!(ncpu(), NCPU);
+
+    init(1);
+    assert_eq!(ncpu(), 1);
+    *SpinLock::new(0).lock() += 1;
+}
+
+#[test]
+#[should_panic(expected = "KERNEL_SYNC_NCPU")]
+fn too_many_cpus() {
+    init(NCPU + 1);
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::ThreadArch;
+use kernel_sync::{
+    arch::Arch, bh_disabled, local_bh_disable, local_irq_save, pop_off, BottomHalf, IrqState,
+    NoIrq, RwSpinLock, SpinLock, TicketLock,
+};
+
+#[test]
+fn test() {
+    common::init();
+    ThreadArch.intr_on();
+
+    let lock = SpinLock::new(0);
+    {
+        let _irq = local_irq_save();
+        assert!(!ThreadArch.intr_get());
+        *lock.lock() += 1;
+        // Still disabled by the outer guard.
+        assert!(!ThreadArch.intr_get());
+    }
+    assert!(ThreadArch.intr_get());
+
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+
+        // A thread switching context while holding its own lock.
+        let irq = IrqState::save();
+        irq.restore();
+    }
+    assert!(ThreadArch.intr_get());
+
+    // Interrupts disabled before stay disabled.
+    ThreadArch.intr_off();
+    assert_eq!(*lock.lock(), 2);
+    assert!(!ThreadArch.intr_get());
+}
+
+#[test]
+fn switch() {
+    common::init();
+    ThreadArch.intr_on();
+
+    let irq = local_irq_save();
+    let state = IrqState::save();
+    common::on_other_cpu(|| {
+        // The scheduler of another CPU switches back to this thread with interrupts disabled.
+        core::mem::forget(local_irq_save());
+        state.restore();
+        drop(irq);
+        assert!(ThreadArch.intr_get());
+    });
+
+    // The scheduler of the first CPU releases its own guard in turn.
+    ThreadArch.intr_off();
+    pop_off();
+    assert!(ThreadArch.intr_get());
+}
+
+#[test]
+fn policy() {
+    common::init();
+    ThreadArch.intr_on();
+
+    static COUNTER: SpinLock<usize, NoIrq> = SpinLock::with_policy(0);
+    *COUNTER.lock() += 1;
+    assert!(ThreadArch.intr_get());
+    assert_eq!(*COUNTER.lock(), 1);
+
+    let lock: TicketLock<usize, BottomHalf> = TicketLock::with_policy(0);
+    {
+        let _guard = lock.lock();
+        assert!(bh_disabled());
+        assert!(ThreadArch.intr_get());
+        let _nested = local_bh_disable();
+        assert!(bh_disabled());
+    }
+    assert!(!bh_disabled());
+
+    let lock: RwSpinLock<usize, BottomHalf> = RwSpinLock::default();
+    let guard = lock.upgradeable_read().upgrade();
+    assert!(bh_disabled());
+    drop(guard.downgrade());
+    assert!(!bh_disabled());
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use common::ThreadArch;
+use kernel_sync::{arch::Arch, IrqSave, McsLock, NoIrq, RwSpinLock, SpinLock, TicketLock};
+
+#[test]
+fn test() {
+    common::init();
+
+    // Interrupts are disabled in handlers, and by `IrqSave` locks elsewhere.
+    let lock: SpinLock<usize, IrqSave> = SpinLock::new(0);
+    *lock.lock_in_irq() += 1;
+    *lock.try_lock_in_irq().unwrap() += 1;
+    ThreadArch.intr_on();
+    ThreadArch.intr_off();
+    assert_eq!(*lock.lock(), 2);
+
+    // Never used from interrupt handlers.
+    let lock: SpinLock<usize, NoIrq> = SpinLock::with_policy(0);
+    ThreadArch.intr_on();
+    *lock.lock() += 1;
+    ThreadArch.intr_off();
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test]
+fn other_locks() {
+    common::init();
+
+    let lock: TicketLock<usize> = TicketLock::new(0);
+    *lock.lock_in_irq() += 1;
+    *lock.try_lock_in_irq().unwrap() += 1;
+    assert!(lock.try_lock_in_irq().is_some());
+    assert_eq!(*lock.lock(), 2);
+
+    let lock: McsLock<usize> = McsLock::new(0);
+    *lock.lock_in_irq() += 1;
+    *lock.try_lock_in_irq().unwrap() += 1;
+    assert_eq!(*lock.lock(), 2);
+
+    let lock: RwSpinLock<usize> = RwSpinLock::new(0);
+    *lock.write_in_irq() += 1;
+    *lock.try_write_in_irq().unwrap() += 1;
+    {
+        let reader = lock.read_in_irq();
+        assert_eq!(*lock.try_read_in_irq().unwrap(), 2);
+        assert!(lock.try_write_in_irq().is_none());
+        drop(reader);
+    }
+    assert_eq!(*lock.read(), 2);
+
+    // Interrupts are left disabled by locks taken in handlers.
+    assert!(!ThreadArch.intr_get());
+}
+
+#[test]
+#[cfg_attr(not(debug_assertions), ignore = "checked in debug builds only")]
+#[should_panic(expected = "also acquired in interrupt context")]
+fn ticket_irq_then_irq_on() {
+    common::init();
+
+    let lock: TicketLock<usize, NoIrq> = TicketLock::with_policy(0);
+    drop(lock.lock_in_irq());
+    ThreadArch.intr_on();
+    drop(lock.lock());
+}
+
+#[test]
+#[cfg_attr(not(debug_assertions), ignore = "checked in debug builds only")]
+#[should_panic(expected = "also held with interrupts enabled")]
+fn rw_irq_on_then_irq() {
+    common::init();
+
+    let lock: RwSpinLock<usize, NoIrq> = RwSpinLock::with_policy(0);
+    ThreadArch.intr_on();
+    drop(lock.read());
+    ThreadArch.intr_off();
+    drop(lock.write_in_irq());
+}
+
+#[test]
+#[cfg_attr(not(debug_assertions), ignore = "checked in debug builds only")]
+#[should_panic(expected = "also acquired in interrupt context")]
+fn irq_then_irq_on() {
+    common::init();
+
+    let lock: SpinLock<usize, NoIrq> = SpinLock::with_policy(0);
+    drop(lock.lock_in_irq());
+    ThreadArch.intr_on();
+    drop(lock.lock());
+}
+
+#[test]
+#[cfg_attr(not(debug_assertions), ignore = "checked in debug builds only")]
+#[should_panic(expected = "also held with interrupts enabled")]
+fn irq_on_then_irq() {
+    common::init();
+
+    let lock: SpinLock<usize, NoIrq> = SpinLock::with_policy(0);
+    ThreadArch.intr_on();
+    drop(lock.lock());
+    ThreadArch.intr_off();
+    drop(lock.lock_in_irq());
+}
@@ -0,0 +1,84 @@
+#![cfg(feature = "lockdep")]
+
+mod common;
+
+use std::panic::{self, AssertUnwindSafe};
+
+use common::Thread;
+use kernel_sync::{SleepLock, SpinLock, TicketLock};
+
+#[test]
+#[should_panic(expected = "lockdep: possible deadlock")]
+fn abba() {
+    common::init();
+    let a = SpinLock::new(0);
+    let b = TicketLock::new(0);
+
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+
+    let _b = b.lock();
+    let _a = a.lock();
+}
+
+#[test]
+fn inversion_not_acquired() {
+    common::init();
+    let a = SpinLock::new(0);
+    let b = SpinLock::new(0);
+    let c = SpinLock::new(0);
+
+    {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+    let inversion = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _b = b.lock();
+        let _a = a.lock();
+    }));
+    assert!(inversion.is_err());
+
+    // `a` is not held after the report, so `c` is not ordered after it.
+    let _c = c.lock();
+    let _a = a.lock();
+}
+
+#[test]
+fn order() {
+    common::init();
+    let a = SpinLock::new(0);
+    let b = SpinLock::new(0);
+    // Locks created at the same place share a class and are not ordered.
+    let array: Vec<_> = (0..2).map(SpinLock::new).collect();
+
+    for _ in 0..2 {
+        let _a = a.lock();
+        let _b = b.lock();
+    }
+
+    // Trying a lock cannot deadlock.
+    let _b = b.lock();
+    let _a = a.try_lock().unwrap();
+
+    let _second = array[1].lock();
+    let _first = array[0].lock();
+}
+
+#[test]
+#[should_panic(expected = "lockdep: possible deadlock")]
+fn sleep() {
+    common::init();
+    let thread = SpinLock::new(Thread::default());
+    let a = SleepLock::new(0);
+    let b = SleepLock::new(0);
+
+    {
+        let _a = a.lock(&thread);
+        let _b = b.lock(&thread);
+    }
+
+    let _b = b.lock(&thread);
+    let _a = a.lock(&thread);
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use std::{collections::BTreeSet, thread};
+
+use common::Thread;
+use kernel_sync::{SleepLock, SpinLock};
+
+/// Returns the id of `lock`, allocated when a thread sleeps on it.
+fn id(lock: &SleepLock<(), Thread>) -> usize {
+    let main = SpinLock::new(Thread::default());
+    let waiter = SpinLock::new(Thread::default());
+
+    let guard = lock.lock(&main);
+    thread::scope(|s| {
+        s.spawn(|| drop(lock.lock(&waiter)));
+        common::wait_sleeping(&waiter);
+        drop(guard);
+    });
+    let id = waiter.lock().slept_on.unwrap();
+    id
+}
+
+#[test]
+fn test() {
+    const N: usize = 8;
+    common::init();
+
+    // Live locks have distinct ids, kept across sleeps.
+    let locks: Vec<_> = (0..N).map(|_| SleepLock::new(())).collect();
+    let first: Vec<_> = locks.iter().map(id).collect();
+    assert_eq!(locks.iter().map(id).collect::<Vec<_>>(), first);
+    let ids: BTreeSet<_> = first.into_iter().collect();
+    assert_eq!(ids.len(), N);
+
+    // Ids of dropped locks are reused.
+    drop(locks);
+    let locks: Vec<_> = (0..N).map(|_| SleepLock::new(())).collect();
+    let reused: BTreeSet<_> = locks.iter().map(id).collect();
+    assert_eq!(reused, ids);
+
+    // Locks never slept on take no id.
+    drop(locks);
+    let _idle: Vec<_> = (0..N).map(|_| SleepLock::<(), Thread>::new(())).collect();
+    let fresh = SleepLock::new(());
+    assert!(ids.contains(&id(&fresh)));
+}
@@ -0,0 +1,100 @@
+#![cfg(feature = "lock-stat")]
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, Once,
+    },
+    thread,
+    time::Duration,
+};
+
+use kernel_sync::{
+    clock::{set_clock, Clock},
+    for_each_lock_stat, reset_lock_stats, LockStat, LockStatReport, SpinLock, TicketLock,
+};
+
+/// Serializes tests, since [`reset_lock_stats`] clears the statistics of all of them.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// A clock advancing by one tick on every read.
+struct TickClock(AtomicU64);
+
+impl Clock for TickClock {
+    fn now(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+static CLOCK: TickClock = TickClock(AtomicU64::new(0));
+
+/// Registers [`CLOCK`] once, before any test takes a lock.
+fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| unsafe { set_clock(&CLOCK) });
+}
+
+fn stat(line: u32) -> LockStat {
+    let mut found = None;
+    for_each_lock_stat(|stat| {
+        if stat.class.file() == file!() && stat.class.line() == line {
+            found = Some(*stat);
+        }
+    });
+    found.unwrap()
+}
+
+#[test]
+fn contention() {
+    init();
+    let _serial = SERIAL.lock().unwrap();
+
+    let (lock, line) = (SpinLock::new(0), line!());
+    let waiting = AtomicBool::new(false);
+    let guard = lock.lock();
+    thread::scope(|s| {
+        s.spawn(|| {
+            waiting.store(true, Ordering::Relaxed);
+            *lock.lock() += 1;
+        });
+        while !waiting.load(Ordering::Relaxed) {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(10));
+        drop(guard);
+    });
+    *lock.try_lock().unwrap() += 1;
+
+    let stat = stat(line);
+    assert_eq!(stat.acquisitions, 3);
+    assert_eq!(stat.contended, 1);
+    assert!(stat.spins_max > 0);
+    assert_eq!(stat.spins_total, stat.spins_max);
+    assert!(stat.wait_max > 0);
+    assert!(stat.hold_max > 0);
+    assert!(stat.hold_total >= stat.hold_max);
+}
+
+#[test]
+fn report() {
+    init();
+    let _serial = SERIAL.lock().unwrap();
+    let (lock, line) = (TicketLock::new(0), line!());
+    for _ in 0..4 {
+        *lock.lock() += 1;
+    }
+    // Locks created at the same place share a class.
+    let array: Vec<_> = (0..2).map(|_| TicketLock::new(0)).collect();
+    let array_line = line!() - 1;
+    for lock in &array {
+        *lock.lock() += 1;
+    }
+    assert_eq!(stat(array_line).acquisitions, 2);
+
+    let report = LockStatReport.to_string();
+    assert!(report.starts_with("lock_stat version 0.1\n"));
+    assert!(report.contains(&format!("{}:{}:", file!(), line)));
+
+    reset_lock_stats();
+    assert_eq!(stat(line).acquisitions, 0);
+}
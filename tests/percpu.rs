@@ -0,0 +1,18 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel_sync::{PerCpu, NCPU};
+
+static COUNTER: PerCpu<AtomicUsize> = PerCpu::from_array([const { AtomicUsize::new(0) }; NCPU]);
+
+#[test]
+fn test() {
+    COUNTER.with(|counter| counter.fetch_add(1, Ordering::Relaxed));
+    COUNTER.get().fetch_add(1, Ordering::Relaxed);
+
+    let mut sum = 0;
+    COUNTER.for_each_cpu(|_, counter| sum += counter.load(Ordering::Relaxed));
+    assert_eq!(sum, 2);
+
+    let flags = PerCpu::new(true);
+    assert!(flags.all(|flag| *flag));
+}
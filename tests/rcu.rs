@@ -0,0 +1,61 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+
+use kernel_sync::{reclamation, synchronize_rcu, RcuCell, SpinLock};
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+struct Data(usize);
+
+impl Drop for Data {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test() {
+    const N: usize = 10;
+    common::init();
+
+    let cell = RcuCell::new(Box::new(Data(0)));
+
+    let guard = cell.read();
+    unsafe { cell.write(Box::new(Data(1))) };
+
+    // The reader still references the old data, which must not be reclaimed.
+    reclamation();
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 0);
+    assert_eq!(guard.0, 0);
+    drop(guard);
+
+    synchronize_rcu();
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+    assert_eq!(cell.read().0, 1);
+
+    // Concurrent writers serialized by a lock, each waiting for its own grace period.
+    let cell = Arc::new(RcuCell::new(Arc::new(0)));
+    let writer = Arc::new(SpinLock::new(()));
+
+    let (tx, rx) = channel();
+    for _ in 0..N {
+        let (cell, writer, tx) = (Arc::clone(&cell), Arc::clone(&writer), tx.clone());
+        thread::spawn(move || {
+            let lock = writer.lock();
+            let old = **cell.read();
+            unsafe { cell.write(Arc::new(old + 1)) };
+            drop(lock);
+
+            synchronize_rcu();
+            if **cell.read() == N {
+                tx.send(()).unwrap();
+            }
+        });
+    }
+
+    rx.recv().unwrap();
+}
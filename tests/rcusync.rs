@@ -0,0 +1,13 @@
+mod common;
+
+use kernel_sync::{rcu_read_lock, synchronize_rcu};
+
+#[test]
+#[should_panic(expected = "inside an RCU read-side critical section")]
+fn test() {
+    // CPUs told apart, so that RCU readers of the current CPU are checked.
+    common::init();
+
+    rcu_read_lock();
+    synchronize_rcu();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::cell::Cell;
+
+use kernel_sync::{ReentrantSpinLock, SpinLock};
+
+#[test]
+fn test() {
+    common::init();
+    let lock = ReentrantSpinLock::new(Cell::new(0));
+
+    let outer = lock.lock();
+    outer.set(1);
+    {
+        let inner = lock.lock();
+        inner.set(inner.get() + 1);
+        assert_eq!(lock.try_lock().unwrap().get(), 2);
+    }
+    assert!(lock.is_locked());
+
+    // Other CPUs cannot enter.
+    common::on_other_cpu(|| assert!(lock.try_lock().is_none()));
+
+    drop(outer);
+    assert!(!lock.is_locked());
+
+    common::on_other_cpu(|| assert_eq!(lock.lock().get(), 2));
+}
+
+#[test]
+#[cfg_attr(not(debug_assertions), ignore = "checked in debug builds only")]
+#[should_panic(expected = "self-deadlock")]
+fn self_deadlock() {
+    common::init();
+    let lock = SpinLock::new(0);
+    let _guard = lock.lock();
+    let _again = lock.lock();
+}
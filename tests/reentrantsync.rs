@@ -0,0 +1,9 @@
+use kernel_sync::ReentrantSpinLock;
+
+#[test]
+#[should_panic(expected = "without an `Arch` backend telling threads apart")]
+fn no_arch() {
+    // Without a backend, all threads would take the nested path as CPU 0.
+    let lock = ReentrantSpinLock::new(0);
+    drop(lock.lock());
+}
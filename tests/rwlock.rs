@@ -0,0 +1,139 @@
+mod common;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+
+use kernel_sync::RwSpinLock;
+
+#[test]
+fn test() {
+    common::init();
+    const N: usize = 10;
+
+    let data = Arc::new(RwSpinLock::new(0));
+
+    let (tx, rx) = channel();
+    for _ in 0..N {
+        let (data, tx) = (Arc::clone(&data), tx.clone());
+        thread::spawn(move || {
+            let mut lock = data.write();
+            *lock += 1;
+            drop(lock);
+
+            // Many readers can hold the lock at the same time.
+            let r1 = data.read();
+            let r2 = data.read();
+            assert_eq!(*r1, *r2);
+            if *r1 == N {
+                tx.send(()).unwrap();
+            }
+        });
+    }
+
+    rx.recv().unwrap();
+}
+
+#[test]
+fn upgrade() {
+    common::init();
+    let lock = RwSpinLock::new(0);
+
+    let reader = lock.read();
+    let upgradeable = lock.upgradeable_read();
+    // Upgradeable readers block new readers and writers.
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+
+    // Cannot upgrade while a reader is still inside.
+    let upgradeable = upgradeable.try_upgrade().unwrap_err();
+    drop(reader);
+
+    let mut writer = upgradeable.upgrade();
+    *writer += 1;
+    let reader = writer.downgrade();
+    assert_eq!(*reader, 1);
+    assert!(lock.try_write().is_none());
+    drop(reader);
+
+    assert_eq!(lock.reader_count(), 0);
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn downgrade_race() {
+    common::init();
+    const READERS: usize = 4;
+    const ROUNDS: usize = 20_000;
+
+    let lock = RwSpinLock::new(0);
+    let started = AtomicUsize::new(0);
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..READERS {
+            s.spawn(|| {
+                started.fetch_add(1, Ordering::Relaxed);
+                // Failed attempts briefly add a reader to the lock word before backing off.
+                while !done.load(Ordering::Relaxed) {
+                    if let Some(reader) = lock.try_read() {
+                        assert!(*reader <= ROUNDS);
+                    }
+                }
+            });
+        }
+        while started.load(Ordering::Relaxed) < READERS {
+            thread::yield_now();
+        }
+
+        for _ in 0..ROUNDS {
+            let mut writer = loop {
+                if let Some(writer) = lock.try_write() {
+                    break writer;
+                }
+                // A lost reader count leaves the lock word underflowed, and the lock held forever.
+                if lock.reader_count() > READERS + 1 {
+                    done.store(true, Ordering::Relaxed);
+                    panic!("lock word corrupted");
+                }
+            };
+            *writer += 1;
+            drop(writer.downgrade_to_upgradeable());
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+
+    assert_eq!(lock.reader_count(), 0);
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_write().unwrap(), ROUNDS);
+}
+
+#[test]
+fn downgrade_with_upgradeable_waiter() {
+    common::init();
+    let lock = RwSpinLock::new(0);
+
+    let mut writer = lock.write();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let upgradeable = lock.upgradeable_read();
+            assert_eq!(*upgradeable, 1);
+            *upgradeable.upgrade() += 1;
+        });
+        // The waiter sets the UPGRADED bit while spinning.
+        while lock.reader_count() == 0 {
+            thread::yield_now();
+        }
+
+        *writer += 1;
+        let upgradeable = writer.downgrade_to_upgradeable();
+        assert_eq!(lock.reader_count(), 1);
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_upgradeable_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(upgradeable);
+    });
+
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_write().unwrap(), 2);
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::{sync::Mutex, thread};
+
+use common::Thread;
+use kernel_sync::{RwSleepLock, SpinLock};
+
+#[test]
+fn test() {
+    common::init();
+    let thread = SpinLock::new(Thread::default());
+    let lock = RwSleepLock::<_, Thread>::new(0);
+
+    // Many readers can hold the lock at the same time.
+    let r1 = lock.read(&thread);
+    let r2 = lock.read(&thread);
+    assert_eq!(lock.reader_count(), 2);
+    assert!(lock.try_write().is_none());
+    drop(r1);
+    drop(r2);
+
+    let mut w = lock.write(&thread);
+    *w += 1;
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+    drop(w);
+
+    assert!(!lock.is_locked());
+    assert_eq!(*lock.try_read().unwrap(), 1);
+}
+
+#[test]
+fn writer_preference() {
+    common::init();
+    let lock = RwSleepLock::<_, Thread>::new(0);
+    let order = Mutex::new(Vec::new());
+    let (main, writer, reader) = (
+        SpinLock::new(Thread::default()),
+        SpinLock::new(Thread::default()),
+        SpinLock::new(Thread::default()),
+    );
+
+    let guard = lock.read(&main);
+    thread::scope(|s| {
+        s.spawn(|| {
+            *lock.write(&writer) += 1;
+            order.lock().unwrap().push("writer");
+        });
+        common::wait_sleeping(&writer);
+
+        // A waiting writer keeps new readers out.
+        assert!(lock.try_read().is_none());
+        s.spawn(|| {
+            assert_eq!(*lock.read(&reader), 1);
+            order.lock().unwrap().push("reader");
+        });
+        common::wait_sleeping(&reader);
+
+        drop(guard);
+    });
+    assert_eq!(*order.lock().unwrap(), ["writer", "reader"]);
+    assert_eq!(writer.lock().woken, 1);
+    assert_eq!(reader.lock().woken, 1);
+}
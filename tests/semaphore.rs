@@ -0,0 +1,44 @@
+mod common;
+
+use std::thread;
+
+use common::Thread;
+use kernel_sync::{Semaphore, SpinLock};
+
+#[test]
+fn test() {
+    common::init();
+    let thread = SpinLock::new(Thread::default());
+    let sem = Semaphore::<Thread>::new(2);
+
+    sem.down(&thread);
+    sem.down(&thread);
+    assert_eq!(sem.count(), 0);
+    assert!(!sem.down_trylock());
+
+    sem.up();
+    assert!(sem.down_trylock());
+
+    sem.up_n(3);
+    assert_eq!(sem.count(), 3);
+}
+
+#[test]
+fn block() {
+    common::init();
+    let sem = Semaphore::<Thread>::new(0);
+    let waiters: Vec<_> = (0..2).map(|_| SpinLock::new(Thread::default())).collect();
+
+    thread::scope(|s| {
+        for waiter in &waiters {
+            s.spawn(|| sem.down(waiter));
+        }
+
+        // Both threads block on the empty semaphore until resources are released.
+        waiters.iter().for_each(common::wait_sleeping);
+        assert_eq!(sem.count(), 0);
+        sem.up_n(2);
+    });
+    assert_eq!(sem.count(), 0);
+    assert!(waiters.iter().all(|waiter| waiter.lock().woken == 1));
+}
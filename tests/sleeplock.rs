@@ -0,0 +1,46 @@
+mod common;
+
+use std::thread;
+
+use common::Thread;
+use kernel_sync::{SleepLock, SpinLock};
+
+// Sleep locks can be plain statics.
+static LOCK: SleepLock<usize, Thread> = SleepLock::new(0);
+
+#[test]
+fn test() {
+    common::init();
+    let thread = SpinLock::new(Thread::default());
+
+    let mut guard = LOCK.lock(&thread);
+    *guard += 1;
+    assert!(LOCK.is_locked());
+    assert!(LOCK.try_lock().is_none());
+    drop(guard);
+
+    assert_eq!(*LOCK.try_lock().unwrap(), 1);
+}
+
+#[test]
+fn fifo() {
+    common::init();
+    let lock = SleepLock::<_, Thread>::new(Vec::new());
+    let main = SpinLock::new(Thread::default());
+    let waiters: Vec<_> = (0..3).map(|_| SpinLock::new(Thread::default())).collect();
+
+    let guard = lock.lock(&main);
+    thread::scope(|s| {
+        // Each waiter is queued only after the previous one sleeps.
+        for (i, waiter) in waiters.iter().enumerate() {
+            let lock = &lock;
+            s.spawn(move || lock.lock(waiter).push(i));
+            common::wait_sleeping(waiter);
+        }
+        drop(guard);
+    });
+
+    // The lock is handed over in the order the waiters went to sleep.
+    assert_eq!(*lock.try_lock().unwrap(), [0, 1, 2]);
+    assert!(waiters.iter().all(|waiter| waiter.lock().woken == 1));
+}
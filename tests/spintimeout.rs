@@ -0,0 +1,40 @@
+#![cfg(feature = "spin-timeout")]
+
+use std::{sync::Mutex, thread};
+
+use kernel_sync::{set_spin_timeout, set_spin_timeout_handler, SpinLock, SpinTimeout};
+
+static REPORT: Mutex<Option<SpinTimeout>> = Mutex::new(None);
+
+fn handler(timeout: &SpinTimeout) {
+    *REPORT.lock().unwrap() = Some(*timeout);
+}
+
+#[test]
+fn test() {
+    set_spin_timeout(1000);
+    set_spin_timeout_handler(handler);
+
+    let lock = SpinLock::new(0);
+    let (guard, owner_line) = (lock.lock(), line!());
+    let waiter_line = thread::scope(|s| {
+        let waiter = s.spawn(|| {
+            let (mut guard, line) = (lock.lock(), line!());
+            *guard += 1;
+            line
+        });
+        while REPORT.lock().unwrap().is_none() {
+            thread::yield_now();
+        }
+        drop(guard);
+        waiter.join().unwrap()
+    });
+    assert_eq!(*lock.lock(), 1);
+
+    let report = REPORT.lock().unwrap().unwrap();
+    assert_eq!(report.owner, Some(0));
+    assert_eq!(report.owner_site.unwrap().line(), owner_line);
+    assert_eq!(report.site.line(), waiter_line);
+    assert_eq!(report.spins % 1000, 0);
+    assert!(report.to_string().contains("held by CPU 0"));
+}
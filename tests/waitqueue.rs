@@ -0,0 +1,30 @@
+mod common;
+
+use common::Thread;
+use kernel_sync::{SpinLock, WaitQueue};
+
+#[test]
+fn test() {
+    let t1 = SpinLock::new(Thread::default());
+    let t2 = SpinLock::new(Thread::default());
+    let t3 = SpinLock::new(Thread::default());
+
+    let mut queue = WaitQueue::new();
+    unsafe {
+        queue.push(&t1);
+        queue.push(&t2);
+        queue.push(&t3);
+    }
+    assert!(queue.remove(&t3));
+    assert!(!queue.remove(&t3));
+
+    // Only the thread waiting for the longest time is woken up.
+    assert!(queue.wake_one());
+    assert_eq!(t1.lock().woken, 1);
+    assert_eq!(t2.lock().woken, 0);
+
+    assert_eq!(queue.wake_all(), 1);
+    assert_eq!(t2.lock().woken, 1);
+    assert_eq!(t3.lock().woken, 0);
+    assert!(queue.is_empty());
+}